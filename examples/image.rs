@@ -34,7 +34,7 @@ fn main() {
     let w = wasm::deserialize_buffer(&w).unwrap();
 
     let ctx = spirv::Ctx::new();
-    let m = ctx.module(&w);
+    let m = ctx.module(&w).unwrap();
     let spv = spirv::module_bytes(m);
 
     // // Read SPIR-V from file instead of generating it - for debugging
@@ -0,0 +1,65 @@
+use crate::*;
+
+/// Something in a module that we can't compile, found by `check::module`
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub error: CompileError,
+    /// The name of the function it's in, if it's in a function and the module has a name section
+    pub fun_name: Option<String>,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.fun_name {
+            Some(name) => write!(f, "${}: {}", name, self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// Finds everything in the module that `spirv::Ctx::module` can't compile, without generating any SPIR-V.
+/// Where `Ctx::module` stops at the first error, this keeps going, so it finds all of them at once.
+/// If it doesn't find anything, compiling the module with `Ctx::new()` should work.
+pub fn module(w: &wasm::Module) -> Vec<Problem> {
+    module_with(&spirv::Ctx::new(), w)
+}
+
+/// Like `module`, but checks what `ctx` can compile, so it accepts the imported globals given to `Ctx::with_global`.
+pub fn module_with(ctx: &spirv::Ctx, w: &wasm::Module) -> Vec<Problem> {
+    let mut errors = Vec::new();
+    spirv::check(ctx, w, &mut errors);
+    ir::with_stack(w, || ir::check(w, &mut errors));
+
+    let names = function_names(w);
+    errors
+        .into_iter()
+        .map(|error| Problem {
+            fun_name: error
+                .fun()
+                .and_then(|i| names.as_ref()?.get(i))
+                .cloned(),
+            error,
+        })
+        .collect()
+}
+
+/// The names of the module's functions, from the "name" custom section.
+/// We don't use `Module::parse_names`, since parity-wasm gives up on the whole section
+/// if it has a kind of subsection it doesn't know about, and newer toolchains add some.
+fn function_names(w: &wasm::Module) -> Option<wasm::IndexMap<String>> {
+    use wasm::Deserialize;
+
+    let section = w.custom_sections().find(|x| x.name() == "name")?;
+    let mut rdr = std::io::Cursor::new(section.payload());
+    // Each subsection starts with its id and size, and function names are subsection 1
+    while let (Ok(id), Ok(size)) = (
+        wasm::VarUint7::deserialize(&mut rdr),
+        wasm::VarUint32::deserialize(&mut rdr),
+    ) {
+        if u8::from(id) == 1 {
+            return wasm::IndexMap::deserialize(w.functions_space(), &mut rdr).ok();
+        }
+        rdr.set_position(rdr.position() + u64::from(u32::from(size)));
+    }
+    None
+}
@@ -0,0 +1,102 @@
+use crate::*;
+
+/// Why we couldn't compile a module.
+/// Function indices count imported functions too, like `call` does,
+/// and instruction offsets are the index of the instruction in the function body, as it is in the original module.
+#[derive(Debug, Clone)]
+pub enum CompileError {
+    /// An instruction wasm-vk doesn't support
+    Unsupported {
+        fun: u32,
+        offset: usize,
+        instruction: String,
+    },
+    /// The function body isn't valid wasm, for example because it pops from an empty stack
+    Invalid {
+        fun: u32,
+        offset: usize,
+        reason: &'static str,
+    },
+    /// An import that isn't one of the ones in the "spv" module that we provide, or has the wrong type
+    Import { name: String, reason: &'static str },
+    /// An element segment we can't put in the function table
+    Element { segment: usize, reason: String },
+    /// A data segment we can't put in linear memory
+    Data { segment: usize, reason: String },
+    /// A global with an initializer that isn't a constant
+    GlobalInit { global: u32 },
+    /// The start function is imported or doesn't exist, so there's no code to use as the entry point
+    Start { fun: u32 },
+    /// The IR we made for a function doesn't type check, which is a bug in wasm-vk
+    Type { fun: u32, error: ir::TypeError },
+}
+
+impl CompileError {
+    /// The index of the function the error is in, if it's about a function
+    pub fn fun(&self) -> Option<u32> {
+        match self {
+            CompileError::Unsupported { fun, .. }
+            | CompileError::Invalid { fun, .. }
+            | CompileError::Start { fun }
+            | CompileError::Type { fun, .. } => Some(*fun),
+            CompileError::Import { .. }
+            | CompileError::Element { .. }
+            | CompileError::Data { .. }
+            | CompileError::GlobalInit { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::Unsupported {
+                fun,
+                offset,
+                instruction,
+            } => write!(
+                f,
+                "Instruction {} at offset {} in function {} isn't supported",
+                instruction, offset, fun
+            ),
+            CompileError::Invalid {
+                fun,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Invalid instruction at offset {} in function {}: {}",
+                offset, fun, reason
+            ),
+            CompileError::Import { name, reason } => {
+                write!(f, "Can't import '{}': {}", name, reason)
+            }
+            CompileError::Element { segment, reason } => {
+                write!(f, "Element segment {} isn't supported: {}", segment, reason)
+            }
+            CompileError::Data { segment, reason } => {
+                write!(f, "Data segment {} isn't supported: {}", segment, reason)
+            }
+            CompileError::GlobalInit { global } => write!(
+                f,
+                "The initializer of global {} isn't a constant or 'spv.id'",
+                global
+            ),
+            CompileError::Start { fun } => write!(
+                f,
+                "The start function {} is imported or doesn't exist, but it needs to be defined in the module",
+                fun
+            ),
+            CompileError::Type { fun, error } => write!(f, "In function {}: {}", fun, error),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Type { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -8,12 +8,16 @@ pub enum INumOp {
     Mul,
     DivS,
     DivU,
+    RemS,
+    RemU,
     Shl,
     ShrU,
     ShrS,
     And,
     Or,
     Xor,
+    Rotl,
+    Rotr,
 }
 
 impl std::fmt::Display for INumOp {
@@ -24,12 +28,16 @@ impl std::fmt::Display for INumOp {
             INumOp::Mul => write!(f, "*"),
             INumOp::DivS => write!(f, "/_s"),
             INumOp::DivU => write!(f, "/_u"),
+            INumOp::RemS => write!(f, "%_s"),
+            INumOp::RemU => write!(f, "%_u"),
             INumOp::Shl => write!(f, "<<"),
             INumOp::ShrU => write!(f, ">>_u"),
             INumOp::ShrS => write!(f, ">>_s"),
             INumOp::And => write!(f, "&"),
             INumOp::Or => write!(f, "|"),
             INumOp::Xor => write!(f, "^"),
+            INumOp::Rotl => write!(f, "`rotl`"),
+            INumOp::Rotr => write!(f, "`rotr`"),
         }
     }
 }
@@ -71,6 +79,161 @@ pub enum CvtOp {
     F32toI32U,
     I32toF32S,
     I32toF32U,
+    F32toI64S,
+    F32toI64U,
+    I64toF32S,
+    I64toF32U,
+    /// i64.extend_i32_s
+    I32toI64S,
+    /// i64.extend_i32_u
+    I32toI64U,
+    /// i32.wrap_i64
+    I64toI32,
+    F64toI32S,
+    F64toI32U,
+    F64toI64S,
+    F64toI64U,
+    I32toF64S,
+    I32toF64U,
+    I64toF64S,
+    I64toF64U,
+    /// f64.promote_f32
+    F32toF64,
+    /// f32.demote_f64
+    F64toF32,
+    /// i32.reinterpret_f32
+    F32asI32,
+    /// f32.reinterpret_i32
+    I32asF32,
+    /// i64.reinterpret_f64
+    F64asI64,
+    /// f64.reinterpret_i64
+    I64asF64,
+    /// i32.extend8_s
+    I32Extend8S,
+    /// i32.extend16_s
+    I32Extend16S,
+    /// i64.extend8_s
+    I64Extend8S,
+    /// i64.extend16_s
+    I64Extend16S,
+    /// i64.extend32_s
+    I64Extend32S,
+    /// The saturating `trunc_sat` versions of the float to int conversions
+    F32toI32SSat,
+    F32toI32USat,
+    F64toI32SSat,
+    F64toI32USat,
+    F32toI64SSat,
+    F32toI64USat,
+    F64toI64SSat,
+    F64toI64USat,
+}
+
+impl CvtOp {
+    /// The type this converts from
+    pub fn from(&self) -> wasm::ValueType {
+        match self {
+            CvtOp::F32toI32S | CvtOp::F32toI32U | CvtOp::F32toI64S | CvtOp::F32toI64U | CvtOp::F32toF64 | CvtOp::F32asI32
+            | CvtOp::F32toI32SSat | CvtOp::F32toI32USat | CvtOp::F32toI64SSat | CvtOp::F32toI64USat => wasm::ValueType::F32,
+            CvtOp::F64toI32S | CvtOp::F64toI32U | CvtOp::F64toI64S | CvtOp::F64toI64U | CvtOp::F64toF32 | CvtOp::F64asI64
+            | CvtOp::F64toI32SSat | CvtOp::F64toI32USat | CvtOp::F64toI64SSat | CvtOp::F64toI64USat => wasm::ValueType::F64,
+            CvtOp::I32toF32S | CvtOp::I32toF32U | CvtOp::I32toI64S | CvtOp::I32toI64U | CvtOp::I32toF64S | CvtOp::I32toF64U | CvtOp::I32asF32
+            | CvtOp::I32Extend8S | CvtOp::I32Extend16S => wasm::ValueType::I32,
+            CvtOp::I64toF32S | CvtOp::I64toF32U | CvtOp::I64toI32 | CvtOp::I64toF64S | CvtOp::I64toF64U | CvtOp::I64asF64
+            | CvtOp::I64Extend8S | CvtOp::I64Extend16S | CvtOp::I64Extend32S => wasm::ValueType::I64,
+        }
+    }
+
+    /// The type this converts to
+    pub fn to(&self) -> wasm::ValueType {
+        match self {
+            CvtOp::F32toI32S | CvtOp::F32toI32U | CvtOp::F64toI32S | CvtOp::F64toI32U | CvtOp::I64toI32 | CvtOp::F32asI32
+            | CvtOp::I32Extend8S | CvtOp::I32Extend16S
+            | CvtOp::F32toI32SSat | CvtOp::F32toI32USat | CvtOp::F64toI32SSat | CvtOp::F64toI32USat => wasm::ValueType::I32,
+            CvtOp::F32toI64S | CvtOp::F32toI64U | CvtOp::F64toI64S | CvtOp::F64toI64U | CvtOp::I32toI64S | CvtOp::I32toI64U | CvtOp::F64asI64
+            | CvtOp::I64Extend8S | CvtOp::I64Extend16S | CvtOp::I64Extend32S
+            | CvtOp::F32toI64SSat | CvtOp::F32toI64USat | CvtOp::F64toI64SSat | CvtOp::F64toI64USat => wasm::ValueType::I64,
+            CvtOp::I32toF32S | CvtOp::I32toF32U | CvtOp::I64toF32S | CvtOp::I64toF32U | CvtOp::F64toF32 | CvtOp::I32asF32 => wasm::ValueType::F32,
+            CvtOp::I32toF64S | CvtOp::I32toF64U | CvtOp::I64toF64S | CvtOp::I64toF64U | CvtOp::F32toF64 | CvtOp::I64asF64 => wasm::ValueType::F64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IUnOp {
+    /// Count leading zeros
+    Clz,
+    /// Count trailing zeros
+    Ctz,
+    /// Count set bits
+    Popcnt,
+}
+
+/// How much of a value a load or store actually accesses, for e.g. `i32.load8_s` or `i64.store32`.
+/// Narrow loads zero- or sign-extend the result; for stores the sign doesn't matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemSize {
+    /// The whole value
+    Full,
+    S8,
+    U8,
+    S16,
+    U16,
+    S32,
+    U32,
+}
+
+impl MemSize {
+    /// The number of bits accessed, or `None` for `Full`
+    pub fn bits(self) -> Option<u32> {
+        match self {
+            MemSize::Full => None,
+            MemSize::S8 | MemSize::U8 => Some(8),
+            MemSize::S16 | MemSize::U16 => Some(16),
+            MemSize::S32 | MemSize::U32 => Some(32),
+        }
+    }
+
+    pub fn signed(self) -> bool {
+        matches!(self, MemSize::S8 | MemSize::S16 | MemSize::S32)
+    }
+}
+
+/// Everything a load or store needs to know about how it accesses memory, other than the pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemArg {
+    pub size: MemSize,
+    /// The known alignment of the pointer, as a power of two.
+    /// This comes from the WASM alignment hint, or the address itself if it's constant.
+    pub align: u32,
+}
+
+impl std::fmt::Display for MemArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.size)
+    }
+}
+
+impl std::fmt::Display for MemSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.bits() {
+            None => Ok(()),
+            Some(b) if self.signed() => write!(f, "{}_s", b),
+            Some(b) => write!(f, "{}_u", b),
+        }
+    }
+}
+
+/// A bulk memory operation, which works on a range of bytes in linear memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkOp {
+    /// Copy(dest, src, len)
+    Copy,
+    /// Fill(dest, byte, len)
+    Fill,
+    /// Init(segment)(dest, offset in segment, len)
+    Init(u32),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -80,6 +243,9 @@ pub enum FUnOp {
     Neg,
     Ceil,
     Floor,
+    Trunc,
+    /// Round to nearest, ties to even
+    Nearest,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -90,6 +256,7 @@ pub enum FNumOp {
     Div,
     Min,
     Max,
+    Copysign,
 }
 
 impl std::fmt::Display for FNumOp {
@@ -101,6 +268,7 @@ impl std::fmt::Display for FNumOp {
             FNumOp::Div => write!(f, "/"),
             FNumOp::Min => write!(f, "`min`"),
             FNumOp::Max => write!(f, "`max`"),
+            FNumOp::Copysign => write!(f, "`copysign`"),
         }
     }
 }
@@ -134,6 +302,29 @@ pub enum Width {
     W64,
 }
 
+impl Width {
+    pub fn bits(self) -> u32 {
+        match self {
+            Width::W32 => 32,
+            Width::W64 => 64,
+        }
+    }
+
+    pub fn int(self) -> wasm::ValueType {
+        match self {
+            Width::W32 => wasm::ValueType::I32,
+            Width::W64 => wasm::ValueType::I64,
+        }
+    }
+
+    pub fn float(self) -> wasm::ValueType {
+        match self {
+            Width::W32 => wasm::ValueType::F32,
+            Width::W64 => wasm::ValueType::F64,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Const {
     I32(i32),
@@ -161,14 +352,19 @@ pub struct Global {
 pub enum Base {
     Nop,
     Const(Const),
-    Load(wasm::ValueType, Box<Base>),
+    Load(wasm::ValueType, MemArg, Box<Base>),
     /// Store(ptr, val)
-    Store(wasm::ValueType, Box<Base>, Box<Base>),
+    Store(wasm::ValueType, MemArg, Box<Base>, Box<Base>),
+    /// Bulk(op, dest, src, len)
+    Bulk(BulkOp, Box<Base>, Box<Base>, Box<Base>),
+    /// Select(ty, a, b, cond) is `a` if `cond` is nonzero, otherwise `b`. Unlike `If`, it always evaluates both.
+    Select(wasm::ValueType, Box<Base>, Box<Base>, Box<Base>),
     INumOp(Width, INumOp, Box<Base>, Box<Base>),
     ICompOp(Width, ICompOp, Box<Base>, Box<Base>),
     FCompOp(Width, FCompOp, Box<Base>, Box<Base>),
     FNumOp(Width, FNumOp, Box<Base>, Box<Base>),
     CvtOp(CvtOp, Box<Base>),
+    IUnOp(Width, IUnOp, Box<Base>),
     FUnOp(Width, FUnOp, Box<Base>),
     SetLocal(Local, Box<Base>),
     SetGlobal(Global, Box<Base>),
@@ -178,15 +374,23 @@ pub enum Base {
     Break,
     Continue,
     Return,
-    Call(u32, Vec<Base>),
-    /// A left-associative block
-    Seq(Box<Base>, Box<Base>),
+    Unreachable,
+    /// Call(function, return type, params)
+    Call(u32, Option<wasm::ValueType>, Vec<Base>),
+    /// A block of code, which evaluates to the value of its last element, if it has one
+    Seq(Vec<Base>),
     If {
         cond: Box<Base>,
         ty: Option<wasm::ValueType>,
         t: Box<Base>,
         f: Box<Base>,
     },
+    /// Runs the first case with `sel` in its list of values, or `default` if there isn't one
+    Switch {
+        sel: Box<Base>,
+        cases: Vec<(Vec<u32>, Base)>,
+        default: Box<Base>,
+    },
 }
 
 impl std::fmt::Display for Base {
@@ -198,20 +402,38 @@ impl std::fmt::Display for Base {
             Base::FNumOp(_w, op, a, b) => write!(f, "{:w$} {} {:w$}", a, op, b, w=w),
             Base::ICompOp(_w, op, a, b) => write!(f, "{:w$} {} {:w$}", a, op, b, w=w),
             Base::FCompOp(_w, op, a, b) => write!(f, "{:w$} {} {:w$}", a, op, b, w=w),
+            Base::IUnOp(_w, op, a) => write!(f, "{:?}({:w$})", op, a, w=w),
             Base::FUnOp(_w, op, a) => write!(f, "{:?}({:w$})", op, a, w=w),
             Base::CvtOp(op, a) => write!(f, "{:?}({:w$})", op, a, w=w),
             Base::Nop => write!(f, "nop"),
             Base::Const(c) => write!(f, "{:?}", c),
-            Base::Load(t, p) => write!(f, "{}.load({})", t, p),
-            Base::Store(t, p, v) => write!(f, "{}.store({}, {})", t, p, v),
+            Base::Load(t, s, p) => write!(f, "{}.load{}({})", t, s, p),
+            Base::Store(t, s, p, v) => write!(f, "{}.store{}({}, {})", t, s, p, v),
+            Base::Bulk(op, a, b, c) => write!(f, "memory.{:?}({}, {}, {})", op, a, b, c),
+            Base::Select(_, a, b, c) => write!(f, "select({}, {}, {})", a, b, c),
             Base::GetLocal(l) => write!(f, "%{}", l.idx),
             Base::SetLocal(l, v) => write!(f, "%{} = {:w$}", l.idx, v, w=w),
             Base::GetGlobal(l) => write!(f, "{:?}.get", l),
             Base::SetGlobal(l, v) => write!(f, "{:?}.set({})", l, v),
-            Base::Seq(a, b) => write!(f, "{:w$}\n{:w$}{:w$}", a, "", b, w=w-2),
+            Base::Seq(v) => {
+                for (i, x) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n{:w$}", "", w=w-2)?;
+                    }
+                    write!(f, "{:w$}", x, w=w-2)?;
+                }
+                Ok(())
+            }
             Base::If{cond,t,f:fa,..} => write!(f, "if\n{s:w$}{:w$}\n{s:w2$}then\n{s:w$}{:w$}\n{s:w2$}else\n{s:w$}{:w$}\n{s:w2$}end", cond, t, fa, s="", w=w, w2=w-2),
             Base::Loop(a) => write!(f, "loop\n{s:w$}{:w$}\n{s:w2$}end", a, w=w, s="", w2=w-2),
-            Base::Call(fun, p) => {
+            Base::Switch { sel, cases, default } => {
+                write!(f, "switch {:w$}\n", sel, w=w)?;
+                for (vals, x) in cases {
+                    write!(f, "{s:w2$}case {:?}\n{s:w$}{:w$}\n", vals, x, s="", w=w, w2=w-2)?;
+                }
+                write!(f, "{s:w2$}default\n{s:w$}{:w$}\n{s:w2$}end", default, s="", w=w, w2=w-2)
+            }
+            Base::Call(fun, _, p) => {
                 write!(f, "call {}(", fun)?;
                 for i in p {
                     write!(f, "{:w$},", i, w=w)?;
@@ -221,87 +443,192 @@ impl std::fmt::Display for Base {
             Base::Break => write!(f, "break"),
             Base::Continue => write!(f, "continue"),
             Base::Return => write!(f, "return"),
+            Base::Unreachable => write!(f, "unreachable"),
         };
         r
     }
 }
 
 impl Base {
-    fn map(self, f: impl Copy + Fn(Self) -> Self) -> Self {
+    /// The type of the value this evaluates to, if it has one
+    pub fn ty(&self) -> Option<wasm::ValueType> {
+        match self {
+            Base::INumOp(w, _, _, _) | Base::IUnOp(w, _, _) => Some(w.int()),
+            Base::FNumOp(w, _, _, _) | Base::FUnOp(w, _, _) => Some(w.float()),
+            Base::CvtOp(c, _) => Some(c.to()),
+            Base::Const(c) => Some(c.ty()),
+            Base::If { ty, .. } | Base::Call(_, ty, _) => *ty,
+            Base::GetGlobal(Global { ty, .. }) => Some(ty.content_type()),
+            Base::Load(ty, _, _) | Base::Select(ty, _, _, _) | Base::GetLocal(Local { ty, .. }) => Some(*ty),
+            Base::FCompOp(_,_,_,_) | Base::ICompOp(_,_,_,_) => Some(wasm::ValueType::I32),
+            Base::Break | Base::Continue | Base::Loop(_) | Base::Nop | Base::Return | Base::Unreachable | Base::Switch { .. } | Base::Store(_, _, _, _) | Base::Bulk(_, _, _, _) | Base::SetGlobal(_,_) | Base::SetLocal(_,_) => None,
+            Base::Seq(v) => v.last().and_then(|x| x.ty()),
+        }
+    }
+
+    /// Whether control never reaches the end of this, so it can stand in for a value of any type
+    fn diverges(&self) -> bool {
         match self {
-            Base::INumOp(w, op, a, b) => {
-                f(Base::INumOp(w, op, Box::new(a.map(f)), Box::new(b.map(f))))
+            Base::Break | Base::Continue | Base::Return | Base::Unreachable => true,
+            Base::Seq(v) => v.iter().any(|x| x.diverges()),
+            Base::If { t, f, .. } => t.diverges() && f.diverges(),
+            Base::Switch { cases, default, .. } => default.diverges() && cases.iter().all(|(_, x)| x.diverges()),
+            _ => false,
+        }
+    }
+
+    /// Checks that this evaluates to a value of type `ty`, or doesn't need to because it never finishes
+    fn expect(&self, ty: wasm::ValueType, sigs: &[wasm::FunctionType]) -> Result<(), TypeError> {
+        match self.check(sigs)? {
+            Some(t) if t == ty => Ok(()),
+            _ if self.diverges() => Ok(()),
+            found => Err(TypeError {
+                expected: Some(ty),
+                found,
+                node: self.to_string(),
+            }),
+        }
+    }
+
+    /// Type checks this expression, returning the type of its value.
+    /// `sigs` is the signature of every function in the module, including imports.
+    pub fn check(&self, sigs: &[wasm::FunctionType]) -> Result<Option<wasm::ValueType>, TypeError> {
+        use wasm::ValueType::I32;
+        match self {
+            Base::INumOp(w, _, a, b) => {
+                a.expect(w.int(), sigs)?;
+                b.expect(w.int(), sigs)?;
             }
-            Base::ICompOp(w, op, a, b) => {
-                f(Base::ICompOp(w, op, Box::new(a.map(f)), Box::new(b.map(f))))
+            Base::ICompOp(w, _, a, b) => {
+                a.expect(w.int(), sigs)?;
+                b.expect(w.int(), sigs)?;
             }
-            Base::FCompOp(w, op, a, b) => {
-                f(Base::FCompOp(w, op, Box::new(a.map(f)), Box::new(b.map(f))))
+            Base::FNumOp(w, _, a, b) | Base::FCompOp(w, _, a, b) => {
+                a.expect(w.float(), sigs)?;
+                b.expect(w.float(), sigs)?;
             }
-            Base::FNumOp(w, op, a, b) => {
-                f(Base::FNumOp(w, op, Box::new(a.map(f)), Box::new(b.map(f))))
+            Base::IUnOp(w, _, a) => a.expect(w.int(), sigs)?,
+            Base::FUnOp(w, _, a) => a.expect(w.float(), sigs)?,
+            Base::CvtOp(c, a) => a.expect(c.from(), sigs)?,
+            Base::Load(_, _, p) => p.expect(I32, sigs)?,
+            Base::Store(ty, _, p, v) => {
+                p.expect(I32, sigs)?;
+                v.expect(*ty, sigs)?;
             }
-            Base::FUnOp(w, op, a) => f(Base::FUnOp(w, op, Box::new(a.map(f)))),
-            Base::CvtOp(op, a) => f(Base::CvtOp(op, Box::new(a.map(f)))),
-            Base::Seq(a, b) => f(Base::Seq(Box::new(a.map(f)), Box::new(b.map(f)))),
-            Base::Store(t, a, b) => f(Base::Store(t, Box::new(a.map(f)), Box::new(b.map(f)))),
-            Base::SetLocal(u, x) => f(Base::SetLocal(u, Box::new(x.map(f)))),
-            Base::SetGlobal(u, x) => f(Base::SetGlobal(u, Box::new(x.map(f)))),
-            Base::Load(t, p) => f(Base::Load(t, Box::new(p.map(f)))),
-            Base::Loop(a) => f(Base::Loop(Box::new(a.map(f)))),
-            Base::If { cond, t, f: fa, ty } => f(Base::If {
-                cond: Box::new(cond.map(f)),
-                t: Box::new(t.map(f)),
-                f: Box::new(fa.map(f)),
-                ty,
-            }),
-            Base::Call(i, params) => f(Base::Call(
-                i,
-                params.into_iter().map(|x| x.map(f)).collect(),
-            )),
-            x => f(x),
+            Base::Bulk(_, a, b, c) => {
+                a.expect(I32, sigs)?;
+                b.expect(I32, sigs)?;
+                c.expect(I32, sigs)?;
+            }
+            Base::Select(ty, a, b, cond) => {
+                a.expect(*ty, sigs)?;
+                b.expect(*ty, sigs)?;
+                cond.expect(I32, sigs)?;
+            }
+            Base::SetLocal(l, v) => v.expect(l.ty, sigs)?,
+            Base::SetGlobal(g, v) => v.expect(g.ty.content_type(), sigs)?,
+            Base::Loop(a) => {
+                a.check(sigs)?;
+            }
+            Base::Seq(v) => {
+                for x in v {
+                    x.check(sigs)?;
+                }
+            }
+            Base::If { cond, ty, t, f } => {
+                cond.expect(I32, sigs)?;
+                match ty {
+                    Some(ty) => {
+                        t.expect(*ty, sigs)?;
+                        f.expect(*ty, sigs)?;
+                    }
+                    None => {
+                        t.check(sigs)?;
+                        f.check(sigs)?;
+                    }
+                }
+            }
+            Base::Switch { sel, cases, default } => {
+                sel.expect(I32, sigs)?;
+                for (_, x) in cases {
+                    x.check(sigs)?;
+                }
+                default.check(sigs)?;
+            }
+            Base::Call(i, ty, params) => {
+                let sig = sigs.get(*i as usize).ok_or_else(|| TypeError {
+                    expected: None,
+                    found: None,
+                    node: format!("{} (function {} doesn't exist)", self, i),
+                })?;
+                if params.len() != sig.params().len() {
+                    return Err(TypeError {
+                        expected: None,
+                        found: None,
+                        node: format!("{} (function {} takes {} parameters)", self, i, sig.params().len()),
+                    });
+                }
+                for (x, t) in params.iter().zip(sig.params()) {
+                    x.expect(*t, sigs)?;
+                }
+                if *ty != sig.return_type() {
+                    return Err(TypeError {
+                        expected: sig.return_type(),
+                        found: *ty,
+                        node: self.to_string(),
+                    });
+                }
+            }
+            Base::Nop | Base::Const(_) | Base::GetLocal(_) | Base::GetGlobal(_) | Base::Break | Base::Continue | Base::Return | Base::Unreachable => (),
         }
+        Ok(self.ty())
     }
 
-    fn fold_leaves<T>(&self, start: T, f: &impl Fn(T, &Self) -> T) -> T {
+    /// The nodes directly inside this one, in the order they run
+    fn children(&self) -> Vec<&Base> {
         match self {
-            Base::If { t, f: fa, cond, .. } => fa.fold_leaves(t.fold_leaves(cond.fold_leaves(start, f), f), f),
-            Base::Seq(a, b)
-            | Base::INumOp(_, _, a, b)
+            Base::If { cond, t, f, .. } => vec![&**cond, &**t, &**f],
+            Base::Switch { sel, cases, default } => std::iter::once(&**sel)
+                .chain(cases.iter().map(|(_, x)| x))
+                .chain(std::iter::once(&**default))
+                .collect(),
+            Base::INumOp(_, _, a, b)
+            | Base::FNumOp(_, _, a, b)
             | Base::ICompOp(_, _, a, b)
             | Base::FCompOp(_, _, a, b)
-            | Base::FNumOp(_, _, a, b)
-            | Base::Store(_, a, b) => b.fold_leaves(a.fold_leaves(start, f), f),
+            | Base::Store(_, _, a, b) => vec![&**a, &**b],
+            Base::Bulk(_, a, b, c) | Base::Select(_, a, b, c) => vec![&**a, &**b, &**c],
             Base::Loop(x)
             | Base::SetLocal(_, x)
             | Base::SetGlobal(_, x)
-            | Base::Load(_, x)
+            | Base::Load(_, _, x)
             | Base::CvtOp(_, x)
-            | Base::FUnOp(_, _, x) => x.fold_leaves(start, f),
-            Base::Call(_, params) => params.iter().fold(start, |acc, x| x.fold_leaves(acc, f)),
-            x => f(start, x),
+            | Base::IUnOp(_, _, x)
+            | Base::FUnOp(_, _, x) => vec![&**x],
+            Base::Call(_, _, v) | Base::Seq(v) => v.iter().collect(),
+            Base::Nop
+            | Base::Const(_)
+            | Base::GetLocal(_)
+            | Base::GetGlobal(_)
+            | Base::Break
+            | Base::Continue
+            | Base::Return
+            | Base::Unreachable => Vec::new(),
         }
     }
 
+    /// Calls `f` on every node, parents before their children.
+    /// It keeps its own stack instead of recursing, so it works on functions of any size.
+    /// Most other passes recurse, so they run with a stack sized for the module, see `with_stack`.
     pub fn fold<T>(&self, start: T, f: &impl Fn(T, &Self) -> T) -> T {
-        let n = f(start, self);
-        match self {
-            Base::If { t, f: fa, cond, .. } => fa.fold(t.fold(cond.fold(n, f), f), f),
-            Base::Seq(a, b)
-            | Base::INumOp(_, _, a, b)
-            | Base::FNumOp(_, _, a, b)
-            | Base::ICompOp(_, _, a, b)
-            | Base::FCompOp(_, _, a, b)
-            | Base::Store(_, a, b) => b.fold(a.fold(n, f), f),
-            Base::Loop(x)
-            | Base::SetLocal(_, x)
-            | Base::SetGlobal(_, x)
-            | Base::Load(_, x)
-            | Base::CvtOp(_, x)
-            | Base::FUnOp(_, _, x) => x.fold(n, f),
-            Base::Call(_, params) => params.iter().fold(n, |acc, x| x.fold_leaves(acc, f)),
-            _ => n,
+        let mut acc = start;
+        let mut stack = vec![self];
+        while let Some(x) = stack.pop() {
+            acc = f(acc, x);
+            // Pushed in reverse, so the first child is next
+            stack.extend(x.children().into_iter().rev());
         }
+        acc
     }
 
     pub fn locals(&self) -> HashSet<Local> {
@@ -319,27 +646,37 @@ impl Base {
 enum Direct {
     Nop,
     Const(Const),
-    Load(wasm::ValueType, Box<Direct>),
+    Load(wasm::ValueType, MemArg, Box<Direct>),
     /// Store(ptr, val)
-    Store(wasm::ValueType, Box<Direct>, Box<Direct>),
+    Store(wasm::ValueType, MemArg, Box<Direct>, Box<Direct>),
+    /// Bulk(op, dest, src, len)
+    Bulk(BulkOp, Box<Direct>, Box<Direct>, Box<Direct>),
+    /// Select(ty, a, b, cond) is `a` if `cond` is nonzero, otherwise `b`. Unlike `If`, it always evaluates both.
+    Select(wasm::ValueType, Box<Direct>, Box<Direct>, Box<Direct>),
     INumOp(Width, INumOp, Box<Direct>, Box<Direct>),
     ICompOp(Width, ICompOp, Box<Direct>, Box<Direct>),
     FCompOp(Width, FCompOp, Box<Direct>, Box<Direct>),
     FNumOp(Width, FNumOp, Box<Direct>, Box<Direct>),
     CvtOp(CvtOp, Box<Direct>),
+    IUnOp(Width, IUnOp, Box<Direct>),
     FUnOp(Width, FUnOp, Box<Direct>),
     SetLocal(Local, Box<Direct>),
     SetGlobal(Global, Box<Direct>),
     GetLocal(Local),
     GetGlobal(Global),
-    Seq(Box<Direct>, Box<Direct>),
+    Seq(Vec<Direct>),
     Label(Box<Direct>),
     Loop(Box<Direct>),
-    Break,
-    Continue,
     Return,
-    Call(u32, Vec<Direct>),
+    Unreachable,
+    Call(u32, Option<wasm::ValueType>, Vec<Direct>),
     Br(u32),
+    /// Like `Base::Switch`, the cases are usually `Br`s from a `br_table`
+    Switch {
+        sel: Box<Direct>,
+        cases: Vec<(Vec<u32>, Direct)>,
+        default: Box<Direct>,
+    },
     // Block(Vec<Direct>),
     If {
         cond: Box<Direct>,
@@ -350,397 +687,332 @@ enum Direct {
 }
 
 impl Direct {
-    fn map(self, f: impl Copy + Fn(Self) -> Self) -> Self {
+    /// The nodes directly inside this one, in the order they run
+    fn children(&self) -> Vec<&Direct> {
         match self {
-            Direct::INumOp(w, op, a, b) => f(Direct::INumOp(
-                w,
-                op,
-                Box::new(a.map(f)),
-                Box::new(b.map(f)),
-            )),
-            Direct::ICompOp(w, op, a, b) => f(Direct::ICompOp(
-                w,
-                op,
-                Box::new(a.map(f)),
-                Box::new(b.map(f)),
-            )),
-            Direct::FCompOp(w, op, a, b) => f(Direct::FCompOp(
-                w,
-                op,
-                Box::new(a.map(f)),
-                Box::new(b.map(f)),
-            )),
-            Direct::FNumOp(w, op, a, b) => f(Direct::FNumOp(
-                w,
-                op,
-                Box::new(a.map(f)),
-                Box::new(b.map(f)),
-            )),
-            Direct::FUnOp(w, op, a) => f(Direct::FUnOp(w, op, Box::new(a.map(f)))),
-            Direct::CvtOp(op, a) => f(Direct::CvtOp(op, Box::new(a.map(f)))),
-            Direct::Seq(a, b) => f(Direct::Seq(Box::new(a.map(f)), Box::new(b.map(f)))),
-            Direct::Store(t, a, b) => f(Direct::Store(t, Box::new(a.map(f)), Box::new(b.map(f)))),
-            Direct::SetLocal(u, x) => f(Direct::SetLocal(u, Box::new(x.map(f)))),
-            Direct::SetGlobal(u, x) => f(Direct::SetGlobal(u, Box::new(x.map(f)))),
-            Direct::Load(t, p) => f(Direct::Load(t, Box::new(p.map(f)))),
-            Direct::Label(a) => f(Direct::Label(Box::new(a.map(f)))),
-            Direct::Loop(a) => f(Direct::Loop(Box::new(a.map(f)))),
-            Direct::If { cond, t, f: fa, ty } => f(Direct::If {
-                cond: Box::new(cond.map(f)),
-                t: Box::new(t.map(f)),
-                f: Box::new(fa.map(f)),
-                ty,
-            }),
-            Direct::Call(i, params) => f(Direct::Call(
-                i,
-                params.into_iter().map(|x| x.map(f)).collect(),
-            )),
-            x => f(x),
+            Direct::If { cond, t, f, .. } => vec![&**cond, &**t, &**f],
+            Direct::Switch { sel, cases, default } => std::iter::once(&**sel)
+                .chain(cases.iter().map(|(_, x)| x))
+                .chain(std::iter::once(&**default))
+                .collect(),
+            Direct::INumOp(_, _, a, b)
+            | Direct::FNumOp(_, _, a, b)
+            | Direct::ICompOp(_, _, a, b)
+            | Direct::FCompOp(_, _, a, b)
+            | Direct::Store(_, _, a, b) => vec![&**a, &**b],
+            Direct::Bulk(_, a, b, c) | Direct::Select(_, a, b, c) => vec![&**a, &**b, &**c],
+            Direct::Label(x)
+            | Direct::Loop(x)
+            | Direct::SetLocal(_, x)
+            | Direct::SetGlobal(_, x)
+            | Direct::Load(_, _, x)
+            | Direct::CvtOp(_, x)
+            | Direct::IUnOp(_, _, x)
+            | Direct::FUnOp(_, _, x) => vec![&**x],
+            Direct::Call(_, _, v) | Direct::Seq(v) => v.iter().collect(),
+            Direct::Nop
+            | Direct::Const(_)
+            | Direct::GetLocal(_)
+            | Direct::GetGlobal(_)
+            | Direct::Br(_)
+            | Direct::Return
+            | Direct::Unreachable => Vec::new(),
         }
     }
 
-    fn map_no_lbl(self, f: impl Copy + Fn(Self) -> Self) -> Self {
-        match self {
-            Direct::INumOp(w, op, a, b) => f(Direct::INumOp(
-                w,
-                op,
-                Box::new(a.map_no_lbl(f)),
-                Box::new(b.map_no_lbl(f)),
-            )),
-            Direct::ICompOp(w, op, a, b) => f(Direct::ICompOp(
-                w,
-                op,
-                Box::new(a.map_no_lbl(f)),
-                Box::new(b.map_no_lbl(f)),
-            )),
-            Direct::FCompOp(w, op, a, b) => f(Direct::FCompOp(
-                w,
-                op,
-                Box::new(a.map_no_lbl(f)),
-                Box::new(b.map_no_lbl(f)),
-            )),
-            Direct::FNumOp(w, op, a, b) => f(Direct::FNumOp(
-                w,
-                op,
-                Box::new(a.map_no_lbl(f)),
-                Box::new(b.map_no_lbl(f)),
-            )),
-            Direct::FUnOp(w, op, a) => f(Direct::FUnOp(w, op, Box::new(a.map_no_lbl(f)))),
-            Direct::CvtOp(op, a) => f(Direct::CvtOp(op, Box::new(a.map_no_lbl(f)))),
-            Direct::Seq(a, b) => f(Direct::Seq(
-                Box::new(a.map_no_lbl(f)),
-                Box::new(b.map_no_lbl(f)),
-            )),
-            Direct::Store(t, a, b) => f(Direct::Store(
-                t,
-                Box::new(a.map_no_lbl(f)),
-                Box::new(b.map_no_lbl(f)),
-            )),
-            Direct::SetLocal(u, x) => f(Direct::SetLocal(u, Box::new(x.map_no_lbl(f)))),
-            Direct::SetGlobal(u, x) => f(Direct::SetGlobal(u, Box::new(x.map_no_lbl(f)))),
-            Direct::Load(t, p) => f(Direct::Load(t, Box::new(p.map_no_lbl(f)))),
-            Direct::If { cond, t, f: fa, ty } => f(Direct::If {
-                cond: Box::new(cond.map_no_lbl(f)),
-                t: Box::new(t.map_no_lbl(f)),
-                f: Box::new(fa.map_no_lbl(f)),
-                ty,
-            }),
-            Direct::Call(i, params) => f(Direct::Call(
-                i,
-                params.into_iter().map(|x| x.map_no_lbl(f)).collect(),
-            )),
-            x => f(x),
+    /// Whether there are more than `depth` levels of nodes below this one
+    fn deeper_than(&self, depth: usize) -> bool {
+        let mut stack = vec![(self, 0)];
+        while let Some((x, d)) = stack.pop() {
+            if d > depth {
+                return true;
+            }
+            stack.extend(x.children().into_iter().map(|x| (x, d + 1)));
         }
+        false
     }
+}
 
-    fn fold_leaves<T>(&self, start: T, f: &impl Fn(T, &Self) -> T) -> T {
-        match self {
-            Direct::Seq(a, b)
-            | Direct::INumOp(_, _, a, b)
-            | Direct::ICompOp(_, _, a, b)
-            | Direct::FCompOp(_, _, a, b)
-            | Direct::FNumOp(_, _, a, b)
-            | Direct::If { t: a, f: b, .. }
-            | Direct::Store(_, a, b) => b.fold_leaves(a.fold_leaves(start, f), f),
-            Direct::SetLocal(_, x)
-            | Direct::SetGlobal(_, x)
-            | Direct::Load(_, x)
-            | Direct::FUnOp(_, _, x)
-            | Direct::CvtOp(_, x) => x.fold_leaves(start, f),
-            Direct::Call(_, params) => params.iter().fold(start, |acc, x| x.fold_leaves(acc, f)),
-            x => f(start, x),
+/// Lowering, type checking and code generation recurse into the IR, so we keep expressions shallow enough not to overflow the stack.
+/// Expressions on the stack that nest deeper than this are stored in a local, so long chains of instructions still work.
+const MAX_EXPR_DEPTH: usize = 64;
+/// Blocks can't be split up like that, so modules with blocks nested deeper than this get a bigger stack, see `with_stack`.
+const SHALLOW_BLOCKS: usize = 64;
+/// The stack the recursive passes need for each level of blocks, and for everything else
+const STACK_PER_BLOCK: usize = 64 * 1024;
+const BASE_STACK: usize = 4 * 1024 * 1024;
+
+/// How deep the blocks, loops and if's in the module's functions nest
+fn block_depth(w: &wasm::Module) -> usize {
+    let bodies = w.code_section().map_or(&[][..], |x| x.bodies());
+    let mut max = 0;
+    for body in bodies {
+        let mut depth = 0usize;
+        for op in body.code().elements() {
+            use wasm::Instruction::*;
+            match op {
+                Block(_) | Loop(_) | If(_) => {
+                    depth += 1;
+                    max = max.max(depth);
+                }
+                End => depth = depth.saturating_sub(1),
+                _ => (),
+            }
         }
     }
+    max
 }
 
-use std::sync::RwLock;
-lazy_static::lazy_static! {
-    static ref NLOCALS: RwLock<u32> = RwLock::new(0);
-}
-
-impl Direct {
-    fn nest(self, max: u32) -> Self {
-        self.map_no_lbl(|x| match x {
-            Direct::Br(i) if i >= max => Direct::Br(i + 1),
-            Direct::Label(a) => Direct::Label(Box::new(a.nest(max + 1))),
-            Direct::Loop(a) => Direct::Loop(Box::new(a.nest(max + 1))),
-            x => x,
-        })
+/// Runs `f`, which lowers and compiles `w`, with enough stack for how deep its blocks nest.
+/// Most modules run on the current thread, and lower their functions on rayon's global thread pool.
+/// Ones with deeper blocks run on a thread pool of their own, with bigger stacks.
+pub(crate) fn with_stack<T: Send>(w: &wasm::Module, f: impl FnOnce() -> T + Send) -> T {
+    let depth = block_depth(w);
+    if depth <= SHALLOW_BLOCKS {
+        return f();
     }
-    fn lift(self, max: u32) -> Self {
-        self.map_no_lbl(|x| match x {
-            Direct::Br(i) if i >= max => Direct::Br(i - 1),
-            Direct::Label(a) => Direct::Label(Box::new(a.lift(max + 1))),
-            Direct::Loop(a) => Direct::Loop(Box::new(a.lift(max + 1))),
-            x => x,
-        })
+    let pool = rayon::ThreadPoolBuilder::new()
+        .stack_size(BASE_STACK + depth * STACK_PER_BLOCK)
+        .build();
+    match pool {
+        Ok(pool) => pool.install(f),
+        // It might still fit on the stack we have
+        Err(_) => f(),
     }
+}
 
-    /// Does this code have any branches, and if so what's the maximum number
-    /// of `labels`s (or `Loop`s) they can branch out of, above this code?
-    /// In `Label(a)`, if `a.br().is_some()`, then `a` might branch out of the `Label`.
-    ///
-    /// For example, `<(block (br 2))>.br() == Some(1)`
-    pub fn br(&self) -> Option<u32> {
-        self.fold_leaves(None, &|acc, x| match (acc, x) {
-            (None, Direct::Br(i)) => Some(*i),
-            (Some(a), Direct::Br(b)) => Some(a.max(*b)),
-            (None, Direct::Label(a)) => a.br().and_then(|x| x.checked_sub(1)),
-            (Some(q), Direct::Label(a)) => Some(
-                a.br()
-                    .and_then(|x| x.checked_sub(1))
-                    .map_or(q, |x| x.max(q)),
-            ),
-            (None, Direct::Loop(a)) => a.br().and_then(|x| x.checked_sub(1)),
-            (Some(q), Direct::Loop(a)) => Some(
-                a.br()
-                    .and_then(|x| x.checked_sub(1))
-                    .map_or(q, |x| x.max(q)),
-            ),
-            (acc, _) => acc,
-        })
-    }
+/// A wasm block or loop that we're lowering code inside of
+struct Label {
+    is_loop: bool,
+    /// Whether we made it into a `Base::Loop`, which we only do if something branches to it
+    is_base_loop: bool,
+}
 
-    /// Replaces any `Br(i)`s with `with`, if `i >= offset`.
-    /// If `exact` is true, only matches `Br(i)` where `i == offset`.
-    fn replace_br(self, with: Self, offset: u32, exact: bool) -> Self {
-        self.map_no_lbl(|x| match x {
-            Direct::Br(i) if (!exact && i >= offset) || i == offset => with.clone(),
-            Direct::Label(a) => {
-                Direct::Label(Box::new(a.replace_br(with.clone(), offset + 1, exact)))
-            }
-            Direct::Loop(a) => {
-                if a.br().map_or(false, |x| x > offset) {
-                    // Break out of this loop, then run "with"
-                    // let l = false
-                    // loop (a.replace_br(Seq(l = true, Op("break")), offset + 1))
-                    // if l { with } else {}
-                    let l = fresh_local();
-                    let l = Local {
-                        ty: wasm::ValueType::I32,
-                        idx: l,
-                    };
+/// The labels we're inside of while lowering a function to `Base`
+struct Labels {
+    /// Innermost last
+    stack: Vec<Label>,
+    /// When a branch needs to get out of more than one `Base::Loop`, this is how many are left
+    exit: Local,
+    /// Whether we used `exit`, so it needs to be initialized
+    used: bool,
+}
 
-                    Direct::Seq(
-                        Box::new(Direct::Seq(
-                            Box::new(Direct::SetLocal(l, Box::new(Direct::Const(Const::I32(0))))),
-                            Box::new(Direct::Loop(Box::new(a.replace_br(
-                                Direct::Seq(
-                                    Box::new(Direct::SetLocal(
-                                        l,
-                                        Box::new(Direct::Const(Const::I32(1))),
-                                    )),
-                                    Box::new(Direct::Break),
-                                ),
-                                offset + 1,
-                                exact,
-                            )))),
-                        )),
-                        Box::new(Direct::If {
-                            cond: Box::new(Direct::GetLocal(l)),
-                            t: Box::new(with.clone()),
-                            f: Box::new(Direct::Nop),
-                            ty: None,
-                        }),
-                    )
-                } else {
-                    Direct::Loop(Box::new(a.replace_br(with.clone(), offset + 1, exact)))
-                }
-            }
-            x => x,
-        })
+impl Fun<Direct> {
+    /// `nlocals` is the number of locals the function uses, so the first free index.
+    fn base(self, nlocals: u32) -> Fun<Base> {
+        let Fun { params, body, ty } = self;
+        let mut labels = Labels {
+            stack: Vec::new(),
+            exit: Local {
+                ty: wasm::ValueType::I32,
+                idx: nlocals,
+            },
+            used: false,
+        };
+        let body = body.base(&mut labels);
+        let body = if labels.used {
+            Base::Seq(vec![
+                Base::SetLocal(labels.exit, Box::new(Base::Const(Const::I32(0)))),
+                body,
+            ])
+        } else {
+            body
+        };
+        Fun { params, body, ty }
     }
+}
 
-    /// We know this code might branch, so here's something that comes after it
-    /// and how many `Seqs` up it is.
-    /// Put it somewhere where it won't be run after a branch.
+impl Direct {
+    /// How many `Label`s (or `Loop`s) out each branch in this code goes, counting from this code.
+    /// Branches to labels inside this code aren't included.
     ///
-    /// This function may clone `x`.
-    fn insert(self, x: Self, offset: u32) -> Self {
-        match &self {
-            Direct::INumOp(_, _, _, _)
-            | Direct::FNumOp(_, _, _, _)
-            | Direct::FUnOp(_, _, _)
-            | Direct::CvtOp(_, _)
-            | Direct::ICompOp(_, _, _, _)
-            | Direct::FCompOp(_, _, _, _)
-            | Direct::SetLocal(_, _)
-            | Direct::SetGlobal(_, _)
-            | Direct::Load(_, _)
-            | Direct::Store(_, _, _)
-                if self.br().is_some() =>
-            {
-                panic!("Branches are currently not supported in arguments to expressions")
+    /// For example, `<(block (br 2))>.branches() == [1]`
+    fn branches(&self) -> Vec<u32> {
+        let mut branches = Vec::new();
+        // Nodes we still need to look at, and how many labels they're inside of
+        let mut stack = vec![(self, 0)];
+        while let Some((x, depth)) = stack.pop() {
+            match x {
+                Direct::Br(i) if *i >= depth => branches.push(i - depth),
+                Direct::Label(a) | Direct::Loop(a) => stack.push((a, depth + 1)),
+                x => stack.extend(x.children().into_iter().map(|x| (x, depth))),
             }
-            _ => (),
-        }
-        match self {
-            Direct::Loop(a) => {
-                if a.br().map_or(true, |x| x == 0) {
-                    Direct::Seq(Box::new(Direct::Loop(a)), Box::new(x))
-                } else {
-                    let l = fresh_local();
-                    let l = Local {
-                        ty: wasm::ValueType::I32,
-                        idx: l,
-                    };
-
-                    Direct::Seq(
-                        Box::new(Direct::Seq(
-                            Box::new(Direct::SetLocal(l, Box::new(Direct::Const(Const::I32(1))))),
-                            Box::new(Direct::Loop(Box::new(a.replace_br(
-                                Direct::Seq(
-                                    Box::new(Direct::SetLocal(
-                                        l,
-                                        Box::new(Direct::Const(Const::I32(0))),
-                                    )),
-                                    Box::new(Direct::Break),
-                                ),
-                                1,
-                                false,
-                            )))),
-                        )),
-                        Box::new(Direct::If {
-                            cond: Box::new(Direct::GetLocal(l)),
-                            t: Box::new(x),
-                            f: Box::new(Direct::Nop),
-                            ty: None,
-                        }),
-                    )
-                }
-            }
-            Direct::Nop => x,
-            Direct::Br(i) if i < offset => x,
-            Direct::Br(i) if i >= offset => Direct::Br(i),
-            Direct::Label(a) => Direct::Label(Box::new(a.insert(x.nest(0), offset + 1))),
-            Direct::Seq(a, b) => {
-                if a.br().is_none() && b.br().is_none() {
-                    Direct::Seq(Box::new(Direct::Seq(a, b)), Box::new(x))
-                } else if a.br().is_some() {
-                    a.insert(*b, 0).insert(x, offset)
-                } else {
-                    Direct::Seq(a, Box::new(b.insert(x, offset)))
-                }
-            }
-            Direct::If { cond, t, f, ty } => {
-                assert_eq!(cond.br(), None, "br not allowed in expressions");
-                if t.br().is_some() || f.br().is_some() {
-                    Direct::If {
-                        cond,
-                        t: Box::new(t.insert(x.clone(), offset)),
-                        f: Box::new(f.insert(x, offset)),
-                        ty,
-                    }
-                } else {
-                    Direct::Seq(Box::new(Direct::If { cond, t, f, ty }), Box::new(x))
-                }
-            }
-            op => Direct::Seq(Box::new(op), Box::new(x)),
         }
+        branches
     }
 
-    fn base(self) -> Base {
+    /// Lowers this to `Base`, inside the labels in `labels`.
+    fn base(self, labels: &mut Labels) -> Base {
         match self {
             Direct::Nop => Base::Nop,
             Direct::INumOp(w, op, a, b) => {
-                Base::INumOp(w, op, Box::new(a.base()), Box::new(b.base()))
+                Base::INumOp(w, op, Box::new(a.base(labels)), Box::new(b.base(labels)))
             }
             Direct::FNumOp(w, op, a, b) => {
-                Base::FNumOp(w, op, Box::new(a.base()), Box::new(b.base()))
+                Base::FNumOp(w, op, Box::new(a.base(labels)), Box::new(b.base(labels)))
             }
             Direct::ICompOp(w, op, a, b) => {
-                Base::ICompOp(w, op, Box::new(a.base()), Box::new(b.base()))
+                Base::ICompOp(w, op, Box::new(a.base(labels)), Box::new(b.base(labels)))
             }
             Direct::FCompOp(w, op, a, b) => {
-                Base::FCompOp(w, op, Box::new(a.base()), Box::new(b.base()))
+                Base::FCompOp(w, op, Box::new(a.base(labels)), Box::new(b.base(labels)))
             }
-            Direct::CvtOp(op, a) => Base::CvtOp(op, Box::new(a.base())),
-            Direct::FUnOp(w, op, a) => Base::FUnOp(w, op, Box::new(a.base())),
+            Direct::CvtOp(op, a) => Base::CvtOp(op, Box::new(a.base(labels))),
+            Direct::IUnOp(w, op, a) => Base::IUnOp(w, op, Box::new(a.base(labels))),
+            Direct::FUnOp(w, op, a) => Base::FUnOp(w, op, Box::new(a.base(labels))),
             Direct::Const(c) => Base::Const(c),
             Direct::If { cond, ty, t, f } => Base::If {
-                cond: Box::new(cond.base()),
+                cond: Box::new(cond.base(labels)),
                 ty,
-                t: Box::new(t.base()),
-                f: Box::new(f.base()),
+                t: Box::new(t.base(labels)),
+                f: Box::new(f.base(labels)),
             },
-            Direct::Br(_) => Base::Nop,
-            Direct::GetLocal(l) => Base::GetLocal(l),
-            Direct::GetGlobal(g) => Base::GetGlobal(g),
-            Direct::Label(a) => a.base(),
-            Direct::Seq(a, b) => {
-                if a.br().is_some() {
-                    a.insert(*b, 0).base()
+            Direct::Switch { sel, cases, default } => Base::Switch {
+                sel: Box::new(sel.base(labels)),
+                cases: cases.into_iter().map(|(v, x)| (v, x.base(labels))).collect(),
+                default: Box::new(default.base(labels)),
+            },
+            Direct::Br(i) => {
+                let target = labels.stack.len() - 1 - i as usize;
+                let br = if labels.stack[target].is_loop {
+                    Base::Continue
+                } else {
+                    Base::Break
+                };
+                // The number of `Base::Loop`s we're in that are inside the target
+                let exits = labels.stack[target + 1..].iter().filter(|x| x.is_base_loop).count();
+                if exits == 0 {
+                    br
                 } else {
-                    Base::Seq(Box::new(a.base()), Box::new(b.base()))
+                    // Each of those breaks out and passes it on to the next one, until we get to the target
+                    labels.used = true;
+                    Base::Seq(vec![
+                        Base::SetLocal(labels.exit, Box::new(Base::Const(Const::I32(exits as i32)))),
+                        Base::Break,
+                    ])
                 }
             }
-            Direct::Store(t, a, b) => Base::Store(t, Box::new(a.base()), Box::new(b.base())),
-            Direct::SetLocal(l, v) => Base::SetLocal(l, Box::new(v.base())),
-            Direct::SetGlobal(l, v) => Base::SetGlobal(l, Box::new(v.base())),
-            Direct::Load(t, p) => Base::Load(t, Box::new(p.base())),
-            Direct::Break => Base::Break,
-            Direct::Continue => Base::Continue,
+            Direct::GetLocal(l) => Base::GetLocal(l),
+            Direct::GetGlobal(g) => Base::GetGlobal(g),
+            Direct::Label(a) => a.label(false, labels),
+            Direct::Seq(v) => Base::Seq(v.into_iter().map(|x| x.base(labels)).collect()),
+            Direct::Store(t, s, a, b) => Base::Store(t, s, Box::new(a.base(labels)), Box::new(b.base(labels))),
+            Direct::Bulk(op, a, b, c) => Base::Bulk(
+                op,
+                Box::new(a.base(labels)),
+                Box::new(b.base(labels)),
+                Box::new(c.base(labels)),
+            ),
+            Direct::Select(t, a, b, c) => Base::Select(
+                t,
+                Box::new(a.base(labels)),
+                Box::new(b.base(labels)),
+                Box::new(c.base(labels)),
+            ),
+            Direct::SetLocal(l, v) => Base::SetLocal(l, Box::new(v.base(labels))),
+            Direct::SetGlobal(l, v) => Base::SetGlobal(l, Box::new(v.base(labels))),
+            Direct::Load(t, s, p) => Base::Load(t, s, Box::new(p.base(labels))),
             Direct::Return => Base::Return,
-            Direct::Call(i, params) => {
-                Base::Call(i, params.into_iter().map(|x| x.base()).collect())
+            Direct::Unreachable => Base::Unreachable,
+            Direct::Call(i, t, params) => {
+                Base::Call(i, t, params.into_iter().map(|x| x.base(labels)).collect())
             }
-            // TODO - do we add Break at the end?
-            Direct::Loop(a) => Base::Loop(Box::new(a.replace_br(Direct::Continue, 0, true).base())),
+            Direct::Loop(a) => a.label(true, labels),
+        }
+    }
+
+    /// Lowers the body of a block or loop.
+    /// wasm blocks branch to their end and loops branch to their start, so we make them `Base::Loop`s, which can do both.
+    /// A `Base::Loop` that reaches its end breaks out of it, like a wasm block or loop, and if nothing branches to it we don't need one at all.
+    fn label(self, is_loop: bool, labels: &mut Labels) -> Base {
+        let branches = self.branches();
+        let is_base_loop = branches.contains(&0);
+        let exits = branches.iter().any(|x| *x > 0);
+        labels.stack.push(Label {
+            is_loop,
+            is_base_loop,
+        });
+        let body = self.base(labels);
+        labels.stack.pop();
+
+        if !is_base_loop {
+            return body;
         }
+        let body = Base::Loop(Box::new(body));
+        if !exits {
+            return body;
+        }
+
+        // Something in here branches further out, so it broke out of this loop with `exit` set to the number
+        // of `Base::Loop`s left to go. If that's zero now, we're in the target, so we branch to it
+        let parent = labels
+            .stack
+            .iter()
+            .rev()
+            .find(|x| x.is_base_loop)
+            .expect("Branch to a label that isn't a Base::Loop");
+        let br = if parent.is_loop {
+            Base::Continue
+        } else {
+            Base::Break
+        };
+        let exit = || Box::new(Base::GetLocal(labels.exit));
+        let cmp = |op| {
+            Box::new(Base::ICompOp(
+                Width::W32,
+                op,
+                exit(),
+                Box::new(Base::Const(Const::I32(0))),
+            ))
+        };
+        Base::Seq(vec![
+            body,
+            Base::If {
+                cond: cmp(ICompOp::NEq),
+                ty: None,
+                t: Box::new(Base::Seq(vec![
+                    Base::SetLocal(
+                        labels.exit,
+                        Box::new(Base::INumOp(
+                            Width::W32,
+                            INumOp::Sub,
+                            exit(),
+                            Box::new(Base::Const(Const::I32(1))),
+                        )),
+                    ),
+                    Base::If {
+                        cond: cmp(ICompOp::Eq),
+                        ty: None,
+                        t: Box::new(br),
+                        f: Box::new(Base::Break),
+                    },
+                ])),
+                f: Box::new(Base::Nop),
+            },
+        ])
     }
 
     fn ty(&self) -> Option<wasm::ValueType> {
         match self {
-            Direct::INumOp(Width::W32, _, _, _) => Some(wasm::ValueType::I32),
-            Direct::INumOp(Width::W64, _, _, _) => Some(wasm::ValueType::I64),
-            Direct::FNumOp(Width::W32, _, _, _) => Some(wasm::ValueType::F32),
-            Direct::FNumOp(Width::W64, _, _, _) => Some(wasm::ValueType::F64),
-            Direct::FUnOp(Width::W32, _, _) => Some(wasm::ValueType::F32),
-            Direct::FUnOp(Width::W64, _, _) => Some(wasm::ValueType::F64),
-            Direct::CvtOp(c,_) => Some(match c {
-                CvtOp::F32toI32S | CvtOp::F32toI32U => wasm::ValueType::I32,
-                CvtOp::I32toF32S | CvtOp::I32toF32U => wasm::ValueType::F32,
-            }),
+            Direct::INumOp(w, _, _, _) | Direct::IUnOp(w, _, _) => Some(w.int()),
+            Direct::FNumOp(w, _, _, _) | Direct::FUnOp(w, _, _) => Some(w.float()),
+            Direct::CvtOp(c, _) => Some(c.to()),
             Direct::Const(c) => Some(c.ty()),
             Direct::If { ty, .. } => *ty,
-            Direct::Call(_,_) => panic!("TODO lookup function"),
+            Direct::Call(_, ty, _) => *ty,
             Direct::GetGlobal(Global { ty, .. }) => Some(ty.content_type()),
-            Direct::Load(ty,_) | Direct::GetLocal(Local { ty, .. }) => Some(*ty),
+            Direct::Load(ty, _, _) | Direct::Select(ty, _, _, _) | Direct::GetLocal(Local { ty, .. }) => Some(*ty),
             Direct::FCompOp(_,_,_,_) | Direct::ICompOp(_,_,_,_) => Some(wasm::ValueType::I32),
-            Direct::Br(_) | Direct::Break | Direct::Continue | Direct::Loop(_) | Direct::Nop | Direct::Return | Direct::Store(_,_,_) | Direct::SetGlobal(_,_) | Direct::SetLocal(_,_) => None,
-            Direct::Label(a) | Direct::Seq(_,a) => a.ty(),
+            Direct::Br(_) | Direct::Loop(_) | Direct::Nop | Direct::Return | Direct::Unreachable | Direct::Switch { .. } | Direct::Store(_, _, _, _) | Direct::Bulk(_, _, _, _) | Direct::SetGlobal(_,_) | Direct::SetLocal(_,_) => None,
+            Direct::Label(a) => a.ty(),
+            Direct::Seq(v) => v.last().and_then(|x| x.ty()),
         }
     }
 }
 
-fn fresh_local() -> u32 {
-    let mut lk = NLOCALS.write().unwrap();
-    *lk += 1;
-    *lk - 1
-}
-
 impl Const {
-    fn ty(&self) -> wasm::ValueType {
+    pub fn ty(&self) -> wasm::ValueType {
         match self {
             Const::I32(_) => wasm::ValueType::I32,
             Const::F32(_) => wasm::ValueType::F32,
@@ -759,129 +1031,402 @@ pub struct Fun<T> {
     pub ty: Option<wasm::ValueType>,
 }
 
-pub fn test(w: &wasm::Module) {
-    let d = direct(w);
-    println!("{:?}", d);
-    println!(
-        "Base: {:#?}",
-        d.into_iter()
-            .map(|Fun { params, body, ty }| Fun {
-                params,
-                body: body.base(),
-                ty,
-            })
-            .collect::<Vec<_>>()
-    );
+impl Fun<Base> {
+    /// Type checks the function body against the function's return type
+    pub fn check(&self, sigs: &[wasm::FunctionType]) -> Result<(), TypeError> {
+        match self.ty {
+            Some(ty) => self.body.expect(ty, sigs),
+            None => self.body.check(sigs).map(|_| ()),
+        }
+    }
+}
+
+/// A node in the IR that has the wrong type, which means there's a bug in the frontend
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub expected: Option<wasm::ValueType>,
+    pub found: Option<wasm::ValueType>,
+    pub node: String,
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let show = |t: Option<wasm::ValueType>| t.map_or("nothing".to_string(), |t| t.to_string());
+        write!(
+            f,
+            "Type error: expected {}, found {} in\n{}",
+            show(self.expected),
+            show(self.found),
+            self.node
+        )
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+pub fn test(w: &wasm::Module) -> Result<(), CompileError> {
+    let mut errors = Vec::new();
+    let session = Session::new(w, &mut errors);
+    let (funs, bodies) = session.funs();
+    for (i, (_, body)) in funs.iter().zip(bodies).enumerate() {
+        let (d, nlocals) = session.direct(session.fun_idx(i), body, &mut errors)?;
+        println!("{:?}", d);
+        println!("Base: {:#?}", d.base(nlocals));
+    }
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
-pub fn to_base(w: &wasm::Module) -> Vec<Fun<Base>> {
-    let d = direct(w);
-    // println!("Direct: {:#?}", d);
-    // let b = d
-    //     .into_iter()
-    //     .map(|Fun { params, body }| Fun {
-    //         params,
-    //         body: body.base(),
-    //     })
-    //     .collect();
-    // println!("Base: {:#?}", b);
-    d.into_iter()
-        .map(|Fun { params, body, ty }| Fun {
-            params,
-            body: body.base(),
-            ty,
+/// Translates the module into our IR, and type checks the result
+/// Functions don't depend on each other, so they're lowered in parallel.
+pub fn to_base(w: &wasm::Module) -> Result<Vec<Fun<Base>>, CompileError> {
+    use rayon::prelude::*;
+
+    let mut errors = Vec::new();
+    let session = Session::new(w, &mut errors);
+    if let Some(e) = errors.into_iter().next() {
+        return Err(e);
+    }
+    let (funs, bodies) = session.funs();
+    funs.par_iter()
+        .zip(bodies.par_iter())
+        .enumerate()
+        .map(|(i, (_, body))| {
+            session
+                .lower(session.fun_idx(i), body)
+                .map_err(|e| e.into_iter().next().unwrap())
         })
         .collect()
 }
 
-fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
-    let imports = w
-        .import_section()
-        .map_or_else(Vec::new, |x| x.entries().to_vec());
-    let mut globals: Vec<_> = imports
-        .iter()
-        .map(|x| x.external())
+/// Finds everything in the module's table and functions that we can't compile.
+/// Unlike `to_base`, it keeps going after the first problem.
+pub(crate) fn check(w: &wasm::Module, errors: &mut Vec<CompileError>) {
+    use rayon::prelude::*;
+
+    let session = Session::new(w, errors);
+    let (funs, bodies) = session.funs();
+    let mut found: Vec<CompileError> = funs
+        .par_iter()
+        .zip(bodies.par_iter())
+        .enumerate()
+        .filter_map(|(i, (_, body))| session.lower(session.fun_idx(i), body).err())
+        .flatten()
+        .collect();
+    errors.append(&mut found);
+}
+
+/// Adds the static offset to a load or store pointer, and figures out how aligned it is.
+/// If the pointer is a constant, we fold the offset in and use its real alignment, which may be better than the hint.
+fn mem_arg(ptr: Direct, size: MemSize, align: u32, offset: u32) -> (Direct, MemArg) {
+    match ptr {
+        Direct::Const(Const::I32(p)) => {
+            let p = p.wrapping_add(offset as i32);
+            // Address 0 has 32 trailing zeros, but no access needs more than 8-byte alignment
+            let align = align.max(p.trailing_zeros().min(3));
+            (Direct::Const(Const::I32(p)), MemArg { size, align })
+        }
+        ptr if offset == 0 => (ptr, MemArg { size, align }),
+        ptr => (
+            Direct::INumOp(
+                Width::W32,
+                INumOp::Add,
+                Box::new(ptr),
+                Box::new(Direct::Const(Const::I32(offset as i32))),
+            ),
+            MemArg { size, align },
+        ),
+    }
+}
+
+/// The signature of every function in the module, in index order, so imported functions come first.
+/// Functions with a type that doesn't exist are added to `errors` and get an empty signature.
+fn signatures(w: &wasm::Module, errors: &mut Vec<CompileError>) -> Vec<wasm::FunctionType> {
+    let types = w.type_section().map_or(&[][..], |x| x.types());
+    w.import_section()
+        .into_iter()
+        .flat_map(|x| x.entries())
         .filter_map(|x| {
-            if let wasm::External::Global(g) = x {
-                Some(*g)
+            if let wasm::External::Function(t) = x.external() {
+                Some(*t)
             } else {
                 None
             }
         })
-        .collect();
-    globals.append(&mut w.global_section().map_or_else(Vec::new, |x| {
-        x.entries().iter().map(|x| *x.global_type()).collect()
-    }));
-
-    let mut funs = Vec::new();
-    for (fun, body) in w
-        .function_section()
-        .map(|x| x.entries().to_vec())
-        .unwrap_or_else(Vec::new)
-        .into_iter()
-        .zip(
-            w.code_section()
-                .map(|x| x.bodies().to_vec())
-                .unwrap_or_else(Vec::new),
+        .chain(
+            w.function_section()
+                .into_iter()
+                .flat_map(|x| x.entries())
+                .map(|x| x.type_ref()),
         )
-    {
-        let mut stack = Vec::new();
+        .enumerate()
+        .map(|(fun, t)| match types.get(t as usize) {
+            Some(wasm::Type::Function(f)) => f.clone(),
+            None => {
+                errors.push(CompileError::Invalid {
+                    fun: fun as u32,
+                    offset: 0,
+                    reason: "function type that doesn't exist",
+                });
+                wasm::FunctionType::new(Vec::new(), None)
+            }
+        })
+        .collect()
+}
+
+/// What we need to know about the module to lower its functions.
+/// It's created once per module and only read after that, so functions can be lowered at the same time.
+struct Session<'a> {
+    w: &'a wasm::Module,
+    sigs: Vec<wasm::FunctionType>,
+    globals: Vec<wasm::GlobalType>,
+    /// The function table, filled in from the element segments.
+    /// We need to know its contents, since we lower `call_indirect` to a switch over the possible functions.
+    table: Vec<Option<u32>>,
+    /// Our linear memory can't grow, so it's always the size the module asks for
+    pages: u32,
+    /// Whether each data segment is passive, since `memory.init` only works with those
+    passive: Vec<bool>,
+    /// What `wasm::deserialize_buffer` changed in each function body
+    fixups: Vec<Vec<(u32, wasm::Fixup)>>,
+}
+
+impl<'a> Session<'a> {
+    /// Element segments we can't use are added to `errors` and left out of the table.
+    fn new(w: &'a wasm::Module, errors: &mut Vec<CompileError>) -> Self {
+        let sigs = signatures(w, errors);
+        let imports = w
+            .import_section()
+            .map_or_else(Vec::new, |x| x.entries().to_vec());
+        let mut globals: Vec<_> = imports
+            .iter()
+            .map(|x| x.external())
+            .filter_map(|x| {
+                if let wasm::External::Global(g) = x {
+                    Some(*g)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        globals.append(&mut w.global_section().map_or_else(Vec::new, |x| {
+            x.entries().iter().map(|x| *x.global_type()).collect()
+        }));
+
+        let mut table: Vec<Option<u32>> = Vec::new();
+        // Segments have to fit in the table's initial size, since it can't grow before they're copied in
+        let table_size = w
+            .table_section()
+            .and_then(|x| x.entries().first())
+            .map_or(0, |x| x.limits().initial());
+        let elements = w.elements_section().into_iter().flat_map(|x| x.entries());
+        for (segment, e) in elements.enumerate() {
+            if e.index() != 0 {
+                errors.push(CompileError::Element {
+                    segment,
+                    reason: "wasm-vk only supports one table".to_string(),
+                });
+                continue;
+            }
+            let offset = match e.offset().as_ref().map(|x| x.code()) {
+                // The offset is unsigned, even though it's an i32.const
+                Some([wasm::Instruction::I32Const(i), wasm::Instruction::End]) => *i as u32,
+                x => {
+                    errors.push(CompileError::Element {
+                        segment,
+                        reason: format!("wasm-vk only supports i32.const offsets, got {:?}", x),
+                    });
+                    continue;
+                }
+            };
+            let end = match offset.checked_add(e.members().len() as u32) {
+                Some(end) if end <= table_size => end as usize,
+                _ => {
+                    errors.push(CompileError::Element {
+                        segment,
+                        reason: format!("it doesn't fit in the table, which has {} entries", table_size),
+                    });
+                    continue;
+                }
+            };
+            let offset = offset as usize;
+            if table.len() < end {
+                table.resize(end, None);
+            }
+            if let Some(f) = e.members().iter().find(|f| **f as usize >= sigs.len()) {
+                errors.push(CompileError::Element {
+                    segment,
+                    reason: format!("function {} doesn't exist", f),
+                });
+                continue;
+            }
+            for (i, f) in e.members().iter().enumerate() {
+                table[offset + i] = Some(*f);
+            }
+        }
+
+        let pages = w
+            .memory_section()
+            .and_then(|x| x.entries().first())
+            .map_or(0, |x| x.limits().initial());
+
+        let passive = w
+            .data_section()
+            .map_or_else(Vec::new, |x| x.entries().iter().map(|x| x.passive()).collect());
+
+        Session {
+            w,
+            sigs,
+            globals,
+            table,
+            pages,
+            passive,
+            fixups: wasm::fixups(w),
+        }
+    }
+
+    /// The defined functions' types and bodies, which are in separate sections
+    fn funs(&self) -> (&'a [wasm::Func], &'a [wasm::FuncBody]) {
+        (
+            self.w.function_section().map_or(&[], |x| x.entries()),
+            self.w.code_section().map_or(&[], |x| x.bodies()),
+        )
+    }
+
+    /// The index of the `i`th defined function, counting imported functions
+    fn fun_idx(&self, i: usize) -> u32 {
+        (self.sigs.len() - self.funs().0.len() + i) as u32
+    }
+
+    /// Lowers a function to `Base` and type checks it.
+    /// If we can't, returns everything that's wrong with it, since there can be more than one unsupported instruction.
+    fn lower(&self, idx: u32, body: &wasm::FuncBody) -> Result<Fun<Base>, Vec<CompileError>> {
+        let mut errors = Vec::new();
+        match self.direct(idx, body, &mut errors) {
+            Ok((f, nlocals)) if errors.is_empty() => {
+                let f = f.base(nlocals);
+                f.check(&self.sigs)
+                    .map_err(|error| vec![CompileError::Type { fun: idx, error }])?;
+                Ok(f)
+            }
+            Ok(_) => Err(errors),
+            Err(e) => {
+                errors.push(e);
+                Err(errors)
+            }
+        }
+    }
+
+    /// `fun_idx` is the function's index, which we need for its signature and for errors.
+    /// Also returns the number of locals the function uses, including the temporaries we add.
+    /// Unsupported instructions are added to `unsupported` and then skipped, so we can find the rest of them,
+    /// but other errors mean we can't keep going.
+    fn direct(
+        &self,
+        fun_idx: u32,
+        body: &wasm::FuncBody,
+        unsupported: &mut Vec<CompileError>,
+    ) -> Result<(Fun<Direct>, u32), CompileError> {
+        let Session {
+            w,
+            ref sigs,
+            ref globals,
+            ref table,
+            pages,
+            ref passive,
+            ref fixups,
+        } = *self;
+
+        let mut stack: Vec<Direct> = Vec::new();
 
         enum BlockTy {
             Block(Vec<Direct>),
             Loop(Vec<Direct>),
-            If(Option<wasm::ValueType>, Box<Direct>, Vec<Direct>),
-            Else(Option<wasm::ValueType>, Box<Direct>, Vec<Direct>, Vec<Direct>),
+            If(Box<Direct>, Vec<Direct>),
+            Else(Box<Direct>, Vec<Direct>, Vec<Direct>),
         }
         impl BlockTy {
             fn push(&mut self, op: Direct) {
                 match self {
                     BlockTy::Block(v)
                     | BlockTy::Loop(v)
-                    | BlockTy::If(_, _, v)
-                    | BlockTy::Else(_, _, _, v) => {
+                    | BlockTy::If(_, v)
+                    | BlockTy::Else(_, _, v) => {
                         v.push(op);
                     }
                 }
             }
 
-            fn ty(&self) -> Option<wasm::ValueType> {
-                match self {
-                    BlockTy::If(t, _,_) | BlockTy::Else(t,_,_,_) => *t,
-                    _ => None,
-                }
-            }
-
             fn op(self) -> Direct {
-                fn fold(v: Vec<Direct>) -> Direct {
-                    v.into_iter().fold(Direct::Nop, |acc, x| {
-                        Direct::Seq(Box::new(acc), Box::new(x))
-                    })
-                }
-
                 match self {
-                    BlockTy::If(ty, cond, v) => Direct::If {
+                    BlockTy::If(cond, v) => Direct::If {
                         cond,
-                        t: Box::new(Direct::Label(Box::new(fold(v)))),
+                        t: Box::new(Direct::Label(Box::new(Direct::Seq(v)))),
                         f: Box::new(Direct::Nop),
-                        ty,
+                        ty: None,
                     },
-                    BlockTy::Else(ty, cond, t, f) => Direct::If {
+                    BlockTy::Else(cond, t, f) => Direct::If {
                         cond,
-                        ty,
-                        t: Box::new(Direct::Label(Box::new(fold(t)))),
-                        f: Box::new(Direct::Label(Box::new(fold(f)))),
+                        ty: None,
+                        t: Box::new(Direct::Label(Box::new(Direct::Seq(t)))),
+                        f: Box::new(Direct::Label(Box::new(Direct::Seq(f)))),
                     },
-                    BlockTy::Loop(v) => Direct::Loop(Box::new(fold(v))),
-                    BlockTy::Block(v) => Direct::Label(Box::new(fold(v))),
+                    BlockTy::Loop(v) => Direct::Loop(Box::new(Direct::Seq(v))),
+                    BlockTy::Block(v) => Direct::Label(Box::new(Direct::Seq(v))),
                 }
             }
         }
 
-        let mut blocks = vec![BlockTy::Block(Vec::new())];
+        /// A block we're currently in, and what we need to know to branch out of it with a value
+        struct Frame {
+            block: BlockTy,
+            /// The local that stores the block's result, if it has one.
+            /// Branches to the block set it, and then the block evaluates to it.
+            result: Option<Local>,
+            /// The height of the stack when we entered the block
+            height: usize,
+            /// Whether we're past an unconditional branch, so the rest of the block (or branch of an `if`) can't run
+            unreachable: bool,
+        }
+        impl Frame {
+            fn new(block: BlockTy, result: Option<Local>, height: usize) -> Self {
+                Frame {
+                    block,
+                    result,
+                    height,
+                    unreachable: false,
+                }
+            }
 
-        let ty = fun.type_ref();
-        let wasm::Type::Function(ty) = &w.type_section().unwrap().types()[ty as usize];
+            fn push(&mut self, op: Direct) {
+                self.block.push(op)
+            }
+
+            /// Stores the value at the end of the block (or branch of an `if`) in the result local.
+            /// If the end isn't reachable there might not be a value.
+            fn set_result(&mut self, stack: &mut Vec<Direct>) {
+                if let Some(l) = self.result {
+                    if stack.len() > self.height {
+                        let val = stack.pop().unwrap();
+                        self.push(Direct::SetLocal(l, Box::new(val)));
+                    }
+                }
+            }
+
+            /// The local that a branch to this block needs to put its value in.
+            /// Branches to a loop go to the start, so they don't carry the loop's result.
+            fn br_result(&self) -> Option<Local> {
+                match self.block {
+                    BlockTy::Loop(_) => None,
+                    _ => self.result,
+                }
+            }
+        }
+
+        // A function with a type that doesn't exist was already reported by `Session::new`
+        let ty = &sigs[fun_idx as usize];
         let params = ty.params().to_vec();
         let ret = ty.return_type();
 
@@ -893,13 +1438,108 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
             .flat_map(|x| (0..x.count()).map(move |_| x.value_type()))
             .collect();
         let locals: Vec<_> = params.iter().cloned().chain(locals).collect();
-        if *NLOCALS.read().unwrap() < locals.len() as u32 { *NLOCALS.write().unwrap() = locals.len() as u32; }
+
+        // The temporaries we add go after the function's own locals
+        let nlocals = std::cell::Cell::new(locals.len() as u32);
+        let fresh_local = || {
+            let idx = nlocals.get();
+            nlocals.set(idx + 1);
+            idx
+        };
+        let result = |ty: Option<wasm::ValueType>| {
+            ty.map(|ty| Local {
+                ty,
+                idx: fresh_local(),
+            })
+        };
+
+        // Branching out of the function body is like returning, so its result is the return value
+        let mut blocks = vec![Frame::new(BlockTy::Block(Vec::new()), result(ret), 0)];
+
+        // The instruction we're on, for errors
+        let mut offset;
+        macro_rules! invalid {
+            ($reason:expr) => {
+                return Err(CompileError::Invalid {
+                    fun: fun_idx,
+                    offset,
+                    reason: $reason,
+                })
+            };
+        }
+        // The block a branch `depth` labels out goes to
+        macro_rules! label {
+            ($depth:expr) => {
+                match blocks.len().checked_sub(1 + $depth as usize) {
+                    Some(i) => &blocks[i],
+                    None => invalid!("branch to a label that doesn't exist"),
+                }
+            };
+        }
+        macro_rules! pop {
+            () => {
+                match stack.pop() {
+                    Some(x) => x,
+                    None => invalid!("not enough values on the stack"),
+                }
+            };
+        }
+        // The types of locals and globals, which might not exist
+        macro_rules! local {
+            ($idx:expr) => {
+                match locals.get($idx as usize) {
+                    Some(ty) => *ty,
+                    None => invalid!("local that doesn't exist"),
+                }
+            };
+        }
+        macro_rules! global {
+            ($idx:expr) => {
+                match globals.get($idx as usize) {
+                    Some(ty) => *ty,
+                    None => invalid!("global that doesn't exist"),
+                }
+            };
+        }
+
+        // The values on the stack are only evaluated when the expression that uses them is, which can be after
+        // code we add to the block now. So before adding code that could change them or have side effects,
+        // we store them in temporaries, which keeps wasm's evaluation order.
+        // Constants and our own temporaries can't change, so they stay where they are.
+        macro_rules! spill {
+            () => {
+                for x in stack.iter_mut() {
+                    match x {
+                        Direct::Const(_) => (),
+                        Direct::GetLocal(l) if l.idx >= locals.len() as u32 => (),
+                        _ => {
+                            let l = Local {
+                                ty: x.ty().expect("Values on the stack should have a type"),
+                                idx: fresh_local(),
+                            };
+                            let val = std::mem::replace(x, Direct::GetLocal(l));
+                            blocks.last_mut().unwrap().push(Direct::SetLocal(l, Box::new(val)));
+                        }
+                    }
+                }
+            };
+        }
+
+        // After an unconditional branch, the values left on the stack are never used.
+        // They've been spilled, so any side effects already happened and we can throw them away.
+        macro_rules! diverge {
+            () => {{
+                let frame = blocks.last_mut().unwrap();
+                stack.truncate(frame.height);
+                frame.unreachable = true;
+            }};
+        }
 
         macro_rules! numop {
             ($w:ident, $op:ident) => {{
                 // They're on the stack as [a, b], so pop b and then a
-                let b = stack.pop().unwrap();
-                let a = stack.pop().unwrap();
+                let b = pop!();
+                let a = pop!();
                 stack.push(Direct::INumOp(
                     Width::$w,
                     INumOp::$op,
@@ -911,8 +1551,8 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
         macro_rules! fnumop {
             ($w:ident, $op:ident) => {{
                 // They're on the stack as [a, b], so pop b and then a
-                let b = stack.pop().unwrap();
-                let a = stack.pop().unwrap();
+                let b = pop!();
+                let a = pop!();
                 stack.push(Direct::FNumOp(
                     Width::$w,
                     FNumOp::$op,
@@ -921,23 +1561,29 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
                 ));
             }};
         }
+        macro_rules! iunop {
+            ($w:ident, $op:ident) => {{
+                let a = pop!();
+                stack.push(Direct::IUnOp(Width::$w, IUnOp::$op, Box::new(a)));
+            }};
+        }
         macro_rules! funop {
             ($w:ident, $op:ident) => {{
-                let a = stack.pop().unwrap();
+                let a = pop!();
                 stack.push(Direct::FUnOp(Width::$w, FUnOp::$op, Box::new(a)));
             }};
         }
         macro_rules! cvtop {
             ($op:ident) => {{
-                let a = stack.pop().unwrap();
+                let a = pop!();
                 stack.push(Direct::CvtOp(CvtOp::$op, Box::new(a)));
             }};
         }
         macro_rules! compop {
             ($w:ident, $op:ident) => {{
                 // They're on the stack as [a, b], so pop b and then a
-                let b = stack.pop().unwrap();
-                let a = stack.pop().unwrap();
+                let b = pop!();
+                let a = pop!();
                 stack.push(Direct::ICompOp(
                     Width::$w,
                     ICompOp::$op,
@@ -949,8 +1595,8 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
         macro_rules! fcompop {
             ($w:ident, $op:ident) => {{
                 // They're on the stack as [a, b], so pop b and then a
-                let b = stack.pop().unwrap();
-                let a = stack.pop().unwrap();
+                let b = pop!();
+                let a = pop!();
                 stack.push(Direct::FCompOp(
                     Width::$w,
                     FCompOp::$op,
@@ -960,147 +1606,464 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
             }};
         }
 
-        let fun_tys: Vec<_> = w
-            .import_section()
-            .into_iter()
-            .flat_map(|x| x.entries())
-            .filter_map(|x| {
-                if let wasm::External::Function(t) = x.external() {
-                    Some(*t)
-                } else {
-                    None
-                }
-            })
-            .chain(
-                w.function_section()
-                    .unwrap()
-                    .entries()
-                    .iter()
-                    .map(|x| x.type_ref()),
-            )
-            .collect();
+        macro_rules! load {
+            ($ty:ident, $align:expr, $offset:expr) => {
+                load!($ty, Full, $align, $offset)
+            };
+            ($ty:ident, $size:ident, $align:expr, $offset:expr) => {{
+                let ptr = pop!();
+                let (ptr, arg) = mem_arg(ptr, MemSize::$size, $align, $offset);
+                stack.push(Direct::Load(wasm::ValueType::$ty, arg, Box::new(ptr)))
+            }};
+        }
+        macro_rules! store {
+            ($ty:ident, $align:expr, $offset:expr) => {
+                store!($ty, Full, $align, $offset)
+            };
+            ($ty:ident, $size:ident, $align:expr, $offset:expr) => {{
+                let val = pop!();
+                let ptr = pop!();
+                let (ptr, arg) = mem_arg(ptr, MemSize::$size, $align, $offset);
+                spill!();
+                blocks.last_mut().unwrap().push(Direct::Store(
+                    wasm::ValueType::$ty,
+                    arg,
+                    Box::new(ptr),
+                    Box::new(val),
+                ))
+            }};
+        }
 
-        for op in code.elements() {
+        // How many blocks deep we are in code we're skipping
+        let mut skipped = 0;
+        let body_idx = fun_idx as usize - (sigs.len() - self.funs().0.len());
+        let mut fixups = fixups.get(body_idx).into_iter().flatten().peekable();
+        // Offsets count instructions in the original code, so they don't include the `nop`s added while parsing
+        let mut nops = 0;
+        for (i, op) in code.elements().iter().enumerate() {
+            let fixup = match fixups.peek() {
+                Some(&&(at, fixup)) if at as usize == i => {
+                    fixups.next();
+                    Some(fixup)
+                }
+                _ => None,
+            };
+            if fixup == Some(wasm::Fixup::Nop) {
+                nops += 1;
+                continue;
+            }
+            offset = i - nops;
+            // `wasm::deserialize_buffer` gives us `trunc_sat` as the trapping version, since parity-wasm can't parse it
+            let sat = fixup == Some(wasm::Fixup::TruncSat);
             use wasm::Instruction::*;
+            // Everything under it was checked when it was on top, so only the last value pushed can be too deep
+            if stack.last().map_or(false, |x| x.deeper_than(MAX_EXPR_DEPTH)) {
+                spill!();
+            }
+            // Code after an unconditional branch never runs, and its stack can be any shape, so we skip it
+            if blocks.last().unwrap().unreachable {
+                match op {
+                    Block(_) | Loop(_) | If(_) => {
+                        skipped += 1;
+                        continue;
+                    }
+                    End if skipped > 0 => {
+                        skipped -= 1;
+                        continue;
+                    }
+                    Else if skipped > 0 => continue,
+                    // The end of the block and the start of the `else` branch can be reached again
+                    End | Else => (),
+                    _ => continue,
+                }
+            }
             match op {
                 Call(i) => {
-                    let f = fun_tys[*i as usize]; //w.function_section().unwrap().entries()[*i as usize];
-                    let wasm::Type::Function(f) = &w.type_section().unwrap().types()[f as usize];
-                    let mut params: Vec<_> =
-                        f.params().iter().map(|_x| stack.pop().unwrap()).collect();
+                    let f = match sigs.get(*i as usize) {
+                        Some(f) => f,
+                        None => invalid!("call to a function that doesn't exist"),
+                    };
+                    let mut params = Vec::new();
+                    for _ in f.params() {
+                        params.push(pop!());
+                    }
                     // The arguments are stored on the stack in reverse order
                     params.reverse();
 
                     // It only goes on the stack if it returned something
                     if f.return_type().is_some() {
-                        stack.push(Direct::Call(*i, params))
+                        stack.push(Direct::Call(*i, f.return_type(), params))
                     } else {
-                        blocks.last_mut().unwrap().push(Direct::Call(*i, params))
+                        spill!();
+                        blocks.last_mut().unwrap().push(Direct::Call(*i, None, params))
                     }
                 }
-                Select => {
-                    let cond = stack.pop().unwrap();
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    let ty = b.ty();
-                    assert_eq!(ty, a.ty());
-                    let ty = ty.unwrap();
-
-                    let idx = fresh_local();
-                    let la = Local {
-                        ty,
-                        idx,
+                CallIndirect(ty, _) => {
+                    let types = w.type_section().map_or(&[][..], |x| x.types());
+                    let wasm::Type::Function(f) = match types.get(*ty as usize) {
+                        Some(f) => f,
+                        None => invalid!("call_indirect with a type that doesn't exist"),
                     };
-                    let idx = fresh_local();
-                    let lb = Local {
+                    let idx = pop!();
+                    let mut params = Vec::new();
+                    for _ in f.params() {
+                        params.push(pop!());
+                    }
+                    params.reverse();
+                    spill!();
+
+                    // Each case calls with the same arguments, so we evaluate them first and store them in locals
+                    let params: Vec<_> = params
+                        .into_iter()
+                        .zip(f.params())
+                        .map(|(x, ty)| {
+                            let l = Local {
+                                ty: *ty,
+                                idx: fresh_local(),
+                            };
+                            blocks.last_mut().unwrap().push(Direct::SetLocal(l, Box::new(x)));
+                            l
+                        })
+                        .collect();
+                    let ret = f.return_type().map(|ty| Local {
                         ty,
-                        idx,
+                        idx: fresh_local(),
+                    });
+
+                    // Group the table entries by which function they call, skipping ones with the wrong signature.
+                    // Those trap, like an index outside the table, so they go to the default case.
+                    let mut targets: Vec<(u32, Vec<u32>)> = Vec::new();
+                    for (i, fun) in table.iter().enumerate() {
+                        match fun {
+                            Some(fun) if sigs[*fun as usize] == *f => {
+                                match targets.iter_mut().find(|(f, _)| f == fun) {
+                                    Some((_, vals)) => vals.push(i as u32),
+                                    None => targets.push((*fun, vec![i as u32])),
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                    let cases = targets
+                        .into_iter()
+                        .map(|(fun, vals)| {
+                            let call = Direct::Call(
+                                fun,
+                                ret.map(|l| l.ty),
+                                params.iter().map(|l| Direct::GetLocal(*l)).collect(),
+                            );
+                            match ret {
+                                Some(l) => (vals, Direct::SetLocal(l, Box::new(call))),
+                                None => (vals, call),
+                            }
+                        })
+                        .collect();
+
+                    blocks.last_mut().unwrap().push(Direct::Switch {
+                        sel: Box::new(idx),
+                        cases,
+                        default: Box::new(Direct::Unreachable),
+                    });
+                    if let Some(l) = ret {
+                        stack.push(Direct::GetLocal(l));
+                    }
+                }
+                Bulk(op) => {
+                    use wasm::BulkInstruction::*;
+                    let op = match op {
+                        MemoryCopy => BulkOp::Copy,
+                        MemoryFill => BulkOp::Fill,
+                        // Passive segments are only kept if there's a memory to copy them into
+                        MemoryInit(_) if w.memory_section().is_none() => invalid!("memory.init without a memory"),
+                        MemoryInit(seg) | MemoryDrop(seg) if *seg as usize >= passive.len() => {
+                            invalid!("data segment that doesn't exist")
+                        }
+                        // Active segments are dropped once they're in memory, so they can't be used here
+                        MemoryInit(seg) if passive.get(*seg as usize) == Some(&true) => BulkOp::Init(*seg),
+                        // We can't free the segment, so there's nothing to do
+                        MemoryDrop(_) => continue,
+                        _ => {
+                            unsupported.push(CompileError::Unsupported {
+                                fun: fun_idx,
+                                offset,
+                                instruction: format!("{:?}", op),
+                            });
+                            // Everything but `table.drop` takes three operands
+                            if !matches!(op, TableDrop(_)) {
+                                pop!();
+                                pop!();
+                                pop!();
+                            }
+                            continue;
+                        }
                     };
-
-                    blocks.last_mut().unwrap().push(Direct::SetLocal(la, Box::new(a)));
-                    blocks.last_mut().unwrap().push(Direct::SetLocal(lb, Box::new(b)));
-
-                    let a = Box::new(Direct::GetLocal(la));
-                    let b = Box::new(Direct::GetLocal(lb));
+                    let len = pop!();
+                    let src = pop!();
+                    let dest = pop!();
+                    spill!();
+                    blocks.last_mut().unwrap().push(Direct::Bulk(
+                        op,
+                        Box::new(dest),
+                        Box::new(src),
+                        Box::new(len),
+                    ))
+                }
+                Atomics(op) => {
+                    // Each invocation has its own linear memory, so other invocations could never see an atomic operation,
+                    // and code that uses atomics to share memory between threads would silently do the wrong thing.
+                    use wasm::AtomicsInstruction::*;
+                    use wasm::ValueType::{I32, I64};
+                    unsupported.push(CompileError::Unsupported {
+                        fun: fun_idx,
+                        offset,
+                        instruction: format!("{:?}", op),
+                    });
+                    // Pop the operands and push a placeholder for the result, so we can keep looking for problems
+                    let (operands, result) = match op {
+                        I32AtomicLoad(_) | I32AtomicLoad8u(_) | I32AtomicLoad16u(_) => {
+                            (1, Some(I32))
+                        }
+                        I64AtomicLoad(_) | I64AtomicLoad8u(_) | I64AtomicLoad16u(_)
+                        | I64AtomicLoad32u(_) => (1, Some(I64)),
+                        I32AtomicStore(_) | I32AtomicStore8u(_) | I32AtomicStore16u(_)
+                        | I64AtomicStore(_) | I64AtomicStore8u(_) | I64AtomicStore16u(_)
+                        | I64AtomicStore32u(_) => (2, None),
+                        AtomicWake(_) => (2, Some(I32)),
+                        I32AtomicWait(_) | I64AtomicWait(_) => (3, Some(I32)),
+                        I32AtomicRmwCmpxchg(_)
+                        | I32AtomicRmwCmpxchg8u(_)
+                        | I32AtomicRmwCmpxchg16u(_) => (3, Some(I32)),
+                        I64AtomicRmwCmpxchg(_)
+                        | I64AtomicRmwCmpxchg8u(_)
+                        | I64AtomicRmwCmpxchg16u(_)
+                        | I64AtomicRmwCmpxchg32u(_) => (3, Some(I64)),
+                        I32AtomicRmwAdd(_)
+                        | I32AtomicRmwAdd8u(_)
+                        | I32AtomicRmwAdd16u(_)
+                        | I32AtomicRmwSub(_)
+                        | I32AtomicRmwSub8u(_)
+                        | I32AtomicRmwSub16u(_)
+                        | I32AtomicRmwAnd(_)
+                        | I32AtomicRmwAnd8u(_)
+                        | I32AtomicRmwAnd16u(_)
+                        | I32AtomicRmwOr(_)
+                        | I32AtomicRmwOr8u(_)
+                        | I32AtomicRmwOr16u(_)
+                        | I32AtomicRmwXor(_)
+                        | I32AtomicRmwXor8u(_)
+                        | I32AtomicRmwXor16u(_)
+                        | I32AtomicRmwXchg(_)
+                        | I32AtomicRmwXchg8u(_)
+                        | I32AtomicRmwXchg16u(_) => (2, Some(I32)),
+                        _ => (2, Some(I64)),
+                    };
+                    for _ in 0..operands {
+                        pop!();
+                    }
+                    match result {
+                        Some(I32) => stack.push(Direct::Const(Const::I32(0))),
+                        Some(_) => stack.push(Direct::Const(Const::I64(0))),
+                        None => (),
+                    }
+                }
+                CurrentMemory(_) => stack.push(Direct::Const(Const::I32(pages as i32))),
+                GrowMemory(_) => {
+                    // Growing by 0 pages is allowed and returns the current size, but anything else fails
+                    let delta = pop!();
                     stack.push(Direct::If {
-                        cond: Box::new(cond),
-                        t: a,
-                        f: b,
-                        ty: Some(ty),
-                    })
+                        cond: Box::new(Direct::ICompOp(
+                            Width::W32,
+                            ICompOp::Eq,
+                            Box::new(delta),
+                            Box::new(Direct::Const(Const::I32(0))),
+                        )),
+                        t: Box::new(Direct::Const(Const::I32(pages as i32))),
+                        f: Box::new(Direct::Const(Const::I32(-1))),
+                        ty: Some(wasm::ValueType::I32),
+                    });
+                }
+                Select => {
+                    let cond = pop!();
+                    let b = pop!();
+                    let a = pop!();
+                    let ty = b.ty();
+                    if ty.is_none() || ty != a.ty() {
+                        invalid!("select needs two values of the same type");
+                    }
+                    stack.push(Direct::Select(
+                        ty.unwrap(),
+                        Box::new(a),
+                        Box::new(b),
+                        Box::new(cond),
+                    ))
+                }
+                Br(i) => {
+                    let br = match label!(*i).br_result() {
+                        Some(l) => {
+                            let val = pop!();
+                            Direct::Seq(vec![Direct::SetLocal(l, Box::new(val)), Direct::Br(*i)])
+                        }
+                        None => Direct::Br(*i),
+                    };
+                    // Anything left on the stack is thrown away, but it still needs to run
+                    spill!();
+                    blocks.last_mut().unwrap().push(br);
+                    diverge!();
+                }
+                BrTable(data) => {
+                    let sel = pop!();
+                    // All the targets take the same type of value, if any
+                    for t in data.table.iter() {
+                        label!(*t);
+                    }
+                    let val = match label!(data.default).br_result() {
+                        Some(l) => Some((l, pop!())),
+                        None => None,
+                    };
+                    spill!();
+                    let val = val.map(|(l, val)| {
+                        let tmp = Local {
+                            ty: l.ty,
+                            idx: fresh_local(),
+                        };
+                        blocks.last_mut().unwrap().push(Direct::SetLocal(tmp, Box::new(val)));
+                        tmp
+                    });
+                    let br = |t: u32| match (val, blocks[blocks.len() - 1 - t as usize].br_result()) {
+                        (Some(val), Some(l)) => Direct::Seq(vec![
+                            Direct::SetLocal(l, Box::new(Direct::GetLocal(val))),
+                            Direct::Br(t),
+                        ]),
+                        _ => Direct::Br(t),
+                    };
+                    // Group the values by target, so each target only gets one case.
+                    // Values that go to the default target don't need a case at all.
+                    let mut targets: Vec<(u32, Vec<u32>)> = Vec::new();
+                    for (val, target) in data.table.iter().enumerate() {
+                        if *target == data.default {
+                            continue;
+                        }
+                        match targets.iter_mut().find(|(t, _)| t == target) {
+                            Some((_, vals)) => vals.push(val as u32),
+                            None => targets.push((*target, vec![val as u32])),
+                        }
+                    }
+                    let switch = Direct::Switch {
+                        sel: Box::new(sel),
+                        cases: targets
+                            .into_iter()
+                            .map(|(t, vals)| (vals, br(t)))
+                            .collect(),
+                        default: Box::new(br(data.default)),
+                    };
+                    blocks.last_mut().unwrap().push(switch);
+                    diverge!();
+                }
+                Drop => {
+                    // We still need to run it for side effects
+                    let x = pop!();
+                    spill!();
+                    blocks.last_mut().unwrap().push(x);
+                }
+                Unreachable => {
+                    spill!();
+                    blocks.last_mut().unwrap().push(Direct::Unreachable);
+                    diverge!();
                 }
-                Br(i) => blocks.last_mut().unwrap().push(Direct::Br(*i)),
                 BrIf(i) => {
-                    let cond = stack.pop().unwrap();
+                    let cond = pop!();
+                    let result = label!(*i).br_result();
+                    let val = match result {
+                        Some(_) => Some(pop!()),
+                        None => None,
+                    };
+                    spill!();
+                    let br = match (result, val) {
+                        (Some(l), Some(val)) => {
+                            // The value stays on the stack if we don't branch, so we put it in a local to only evaluate it once
+                            let tmp = Local {
+                                ty: l.ty,
+                                idx: fresh_local(),
+                            };
+                            blocks.last_mut().unwrap().push(Direct::SetLocal(tmp, Box::new(val)));
+                            stack.push(Direct::GetLocal(tmp));
+                            Direct::Seq(vec![
+                                Direct::SetLocal(l, Box::new(Direct::GetLocal(tmp))),
+                                Direct::Br(*i),
+                            ])
+                        }
+                        _ => Direct::Br(*i),
+                    };
                     blocks.last_mut().unwrap().push(Direct::If {
                         cond: Box::new(cond),
-                        t: Box::new(Direct::Br(*i)),
+                        t: Box::new(br),
                         f: Box::new(Direct::Nop),
                         ty: None,
                     })
                 }
                 Nop => (),
                 SetLocal(u) => {
-                    let val = stack.pop().unwrap();
-                    let ty = locals[*u as usize];
+                    let val = pop!();
+                    let ty = local!(*u);
+                    spill!();
                     blocks
                         .last_mut()
                         .unwrap()
                         .push(Direct::SetLocal(Local { ty, idx: *u }, Box::new(val)));
                 }
                 SetGlobal(u) => {
-                    let val = stack.pop().unwrap();
-                    let ty = globals[*u as usize];
+                    let val = pop!();
+                    let ty = global!(*u);
+                    if !ty.is_mutable() {
+                        invalid!("global.set of an immutable global");
+                    }
+                    spill!();
                     blocks
                         .last_mut()
                         .unwrap()
                         .push(Direct::SetGlobal(Global { ty, idx: *u }, Box::new(val)));
                 }
-                I32Load(_, 0) => {
-                    let val = stack.pop().unwrap();
-                    stack.push(Direct::Load(wasm::ValueType::I32, Box::new(val)))
-                }
-                I32Load(_, offset) => {
-                    let val = stack.pop().unwrap();
-                    let val = Direct::INumOp(Width::W32, INumOp::Add, Box::new(val), Box::new(Direct::Const(Const::I32(*offset as i32))));
-                    stack.push(Direct::Load(wasm::ValueType::I32, Box::new(val)))
-                }
-                I32Store(_, 0) => {
-                    let val = stack.pop().unwrap();
-                    let ptr = stack.pop().unwrap();
-                    blocks.last_mut().unwrap().push(Direct::Store(
-                        wasm::ValueType::I32,
-                        Box::new(ptr),
-                        Box::new(val),
-                    ))
-                }
-                I32Store(_, offset) => {
-                    let val = stack.pop().unwrap();
-                    let ptr = stack.pop().unwrap();
-                    let ptr = Direct::INumOp(Width::W32, INumOp::Add, Box::new(ptr), Box::new(Direct::Const(Const::I32(*offset as i32))));
-                    blocks.last_mut().unwrap().push(Direct::Store(
-                        wasm::ValueType::I32,
-                        Box::new(ptr),
-                        Box::new(val),
-                    ))
-                }
+                I32Load(align, offset) => load!(I32, *align, *offset),
+                I64Load(align, offset) => load!(I64, *align, *offset),
+                F32Load(align, offset) => load!(F32, *align, *offset),
+                F64Load(align, offset) => load!(F64, *align, *offset),
+                I32Store(align, offset) => store!(I32, *align, *offset),
+                I64Store(align, offset) => store!(I64, *align, *offset),
+                F32Store(align, offset) => store!(F32, *align, *offset),
+                F64Store(align, offset) => store!(F64, *align, *offset),
+                I32Load8S(align, offset) => load!(I32, S8, *align, *offset),
+                I32Load8U(align, offset) => load!(I32, U8, *align, *offset),
+                I32Load16S(align, offset) => load!(I32, S16, *align, *offset),
+                I32Load16U(align, offset) => load!(I32, U16, *align, *offset),
+                I64Load8S(align, offset) => load!(I64, S8, *align, *offset),
+                I64Load8U(align, offset) => load!(I64, U8, *align, *offset),
+                I64Load16S(align, offset) => load!(I64, S16, *align, *offset),
+                I64Load16U(align, offset) => load!(I64, U16, *align, *offset),
+                I64Load32S(align, offset) => load!(I64, S32, *align, *offset),
+                I64Load32U(align, offset) => load!(I64, U32, *align, *offset),
+                // The sign doesn't matter for stores
+                I32Store8(align, offset) => store!(I32, U8, *align, *offset),
+                I32Store16(align, offset) => store!(I32, U16, *align, *offset),
+                I64Store8(align, offset) => store!(I64, U8, *align, *offset),
+                I64Store16(align, offset) => store!(I64, U16, *align, *offset),
+                I64Store32(align, offset) => store!(I64, U32, *align, *offset),
                 GetGlobal(idx) => stack.push(Direct::GetGlobal(Global {
-                    ty: globals[*idx as usize],
+                    ty: global!(*idx),
                     idx: *idx,
                 })),
                 GetLocal(u) => {
-                    let ty = locals[*u as usize];
+                    let ty = local!(*u);
                     stack.push(Direct::GetLocal(Local { ty, idx: *u }))
                 }
                 TeeLocal(u) => {
-                    let val = stack.pop().unwrap().clone();
-                    let ty = locals[*u as usize];
+                    let val = pop!().clone();
+                    let ty = local!(*u);
                     // This has a side effect but also returns something, and we need the side effect to get executed at the right time
                     // So we use a Seq instead of pushing the Set to blocks.last_mut()
-                    stack.push(Direct::Seq(
-                        Box::new(Direct::SetLocal(Local { ty, idx: *u }, Box::new(val))),
-                        Box::new(Direct::GetLocal(Local { ty, idx: *u })),
-                    ))
+                    stack.push(Direct::Seq(vec![
+                        Direct::SetLocal(Local { ty, idx: *u }, Box::new(val)),
+                        Direct::GetLocal(Local { ty, idx: *u }),
+                    ]))
                 }
                 I32Const(i) => stack.push(Direct::Const(Const::I32(*i))),
                 I64Const(i) => stack.push(Direct::Const(Const::I64(*i))),
@@ -1111,12 +2074,19 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
                 I32Sub => numop!(W32, Sub),
                 I32DivS => numop!(W32, DivS),
                 I32DivU => numop!(W32, DivU),
+                I32RemS => numop!(W32, RemS),
+                I32RemU => numop!(W32, RemU),
                 I32Shl => numop!(W32, Shl),
                 I32ShrS => numop!(W32, ShrS),
                 I32ShrU => numop!(W32, ShrU),
                 I32And => numop!(W32, And),
                 I32Or => numop!(W32, Or),
                 I32Xor => numop!(W32, Xor),
+                I32Rotl => numop!(W32, Rotl),
+                I32Rotr => numop!(W32, Rotr),
+                I32Clz => iunop!(W32, Clz),
+                I32Ctz => iunop!(W32, Ctz),
+                I32Popcnt => iunop!(W32, Popcnt),
                 I32Eq => compop!(W32, Eq),
                 I32Ne => compop!(W32, NEq),
                 I32LeU => compop!(W32, LeU),
@@ -1129,7 +2099,7 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
                 I32GtS => compop!(W32, GtS),
 
                 I32Eqz => {
-                    let a = stack.pop().unwrap();
+                    let a = pop!();
                     let b = Direct::Const(Const::I32(0));
                     stack.push(Direct::ICompOp(
                         Width::W32,
@@ -1139,17 +2109,60 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
                     ))
                 }
 
+                I64Add => numop!(W64, Add),
+                I64Mul => numop!(W64, Mul),
+                I64Sub => numop!(W64, Sub),
+                I64DivS => numop!(W64, DivS),
+                I64DivU => numop!(W64, DivU),
+                I64RemS => numop!(W64, RemS),
+                I64RemU => numop!(W64, RemU),
+                I64Shl => numop!(W64, Shl),
+                I64ShrS => numop!(W64, ShrS),
+                I64ShrU => numop!(W64, ShrU),
+                I64And => numop!(W64, And),
+                I64Or => numop!(W64, Or),
+                I64Xor => numop!(W64, Xor),
+                I64Rotl => numop!(W64, Rotl),
+                I64Rotr => numop!(W64, Rotr),
+                I64Clz => iunop!(W64, Clz),
+                I64Ctz => iunop!(W64, Ctz),
+                I64Popcnt => iunop!(W64, Popcnt),
+                I64Eq => compop!(W64, Eq),
+                I64Ne => compop!(W64, NEq),
+                I64LeU => compop!(W64, LeU),
+                I64LeS => compop!(W64, LeS),
+                I64GeU => compop!(W64, GeU),
+                I64GeS => compop!(W64, GeS),
+                I64LtU => compop!(W64, LtU),
+                I64LtS => compop!(W64, LtS),
+                I64GtU => compop!(W64, GtU),
+                I64GtS => compop!(W64, GtS),
+
+                I64Eqz => {
+                    let a = pop!();
+                    let b = Direct::Const(Const::I64(0));
+                    stack.push(Direct::ICompOp(
+                        Width::W64,
+                        ICompOp::Eq,
+                        Box::new(a),
+                        Box::new(b),
+                    ))
+                }
+
                 F32Min => fnumop!(W32, Min),
                 F32Max => fnumop!(W32, Max),
                 F32Add => fnumop!(W32, Add),
                 F32Sub => fnumop!(W32, Sub),
                 F32Mul => fnumop!(W32, Mul),
                 F32Div => fnumop!(W32, Div),
+                F32Copysign => fnumop!(W32, Copysign),
                 F32Abs => funop!(W32, Abs),
                 F32Neg => funop!(W32, Neg),
                 F32Sqrt => funop!(W32, Sqrt),
                 F32Ceil => funop!(W32, Ceil),
                 F32Floor => funop!(W32, Floor),
+                F32Trunc => funop!(W32, Trunc),
+                F32Nearest => funop!(W32, Nearest),
                 F32Gt => fcompop!(W32, Gt),
                 F32Lt => fcompop!(W32, Lt),
                 F32Ge => fcompop!(W32, Ge),
@@ -1157,64 +2170,160 @@ fn direct(w: &wasm::Module) -> Vec<Fun<Direct>> {
                 F32Eq => fcompop!(W32, Eq),
                 F32Ne => fcompop!(W32, NEq),
 
+                F64Min => fnumop!(W64, Min),
+                F64Max => fnumop!(W64, Max),
+                F64Add => fnumop!(W64, Add),
+                F64Sub => fnumop!(W64, Sub),
+                F64Mul => fnumop!(W64, Mul),
+                F64Div => fnumop!(W64, Div),
+                F64Copysign => fnumop!(W64, Copysign),
+                F64Abs => funop!(W64, Abs),
+                F64Neg => funop!(W64, Neg),
+                F64Sqrt => funop!(W64, Sqrt),
+                F64Ceil => funop!(W64, Ceil),
+                F64Floor => funop!(W64, Floor),
+                F64Trunc => funop!(W64, Trunc),
+                F64Nearest => funop!(W64, Nearest),
+                F64Gt => fcompop!(W64, Gt),
+                F64Lt => fcompop!(W64, Lt),
+                F64Ge => fcompop!(W64, Ge),
+                F64Le => fcompop!(W64, Le),
+                F64Eq => fcompop!(W64, Eq),
+                F64Ne => fcompop!(W64, NEq),
+
+                I32TruncSF32 if sat => cvtop!(F32toI32SSat),
+                I32TruncUF32 if sat => cvtop!(F32toI32USat),
+                I32TruncSF64 if sat => cvtop!(F64toI32SSat),
+                I32TruncUF64 if sat => cvtop!(F64toI32USat),
+                I64TruncSF32 if sat => cvtop!(F32toI64SSat),
+                I64TruncUF32 if sat => cvtop!(F32toI64USat),
+                I64TruncSF64 if sat => cvtop!(F64toI64SSat),
+                I64TruncUF64 if sat => cvtop!(F64toI64USat),
                 I32TruncSF32 => cvtop!(F32toI32S),
                 I32TruncUF32 => cvtop!(F32toI32U),
                 F32ConvertSI32 => cvtop!(I32toF32S),
                 F32ConvertUI32 => cvtop!(I32toF32U),
+                I64TruncSF32 => cvtop!(F32toI64S),
+                I64TruncUF32 => cvtop!(F32toI64U),
+                F32ConvertSI64 => cvtop!(I64toF32S),
+                F32ConvertUI64 => cvtop!(I64toF32U),
+                I64ExtendSI32 => cvtop!(I32toI64S),
+                I64ExtendUI32 => cvtop!(I32toI64U),
+                I32WrapI64 => cvtop!(I64toI32),
+                I32TruncSF64 => cvtop!(F64toI32S),
+                I32TruncUF64 => cvtop!(F64toI32U),
+                I64TruncSF64 => cvtop!(F64toI64S),
+                I64TruncUF64 => cvtop!(F64toI64U),
+                F64ConvertSI32 => cvtop!(I32toF64S),
+                F64ConvertUI32 => cvtop!(I32toF64U),
+                F64ConvertSI64 => cvtop!(I64toF64S),
+                F64ConvertUI64 => cvtop!(I64toF64U),
+                F64PromoteF32 => cvtop!(F32toF64),
+                F32DemoteF64 => cvtop!(F64toF32),
+                I32ReinterpretF32 => cvtop!(F32asI32),
+                F32ReinterpretI32 => cvtop!(I32asF32),
+                I64ReinterpretF64 => cvtop!(F64asI64),
+                F64ReinterpretI64 => cvtop!(I64asF64),
+                SignExt(op) => match op {
+                    wasm::SignExtInstruction::I32Extend8S => cvtop!(I32Extend8S),
+                    wasm::SignExtInstruction::I32Extend16S => cvtop!(I32Extend16S),
+                    wasm::SignExtInstruction::I64Extend8S => cvtop!(I64Extend8S),
+                    wasm::SignExtInstruction::I64Extend16S => cvtop!(I64Extend16S),
+                    wasm::SignExtInstruction::I64Extend32S => cvtop!(I64Extend32S),
+                },
 
-                Loop(_ty) => blocks.push(BlockTy::Loop(Vec::new())),
-                Block(_ty) => blocks.push(BlockTy::Block(Vec::new())),
+                // Blocks go in the enclosing block as statements and leave their result in a local,
+                // so anything under them on the stack has to be evaluated before they start
+                Loop(ty) => {
+                    spill!();
+                    blocks.push(Frame::new(
+                        BlockTy::Loop(Vec::new()),
+                        result(wasm::block_ty_to_option(*ty)),
+                        stack.len(),
+                    ))
+                }
+                Block(ty) => {
+                    spill!();
+                    blocks.push(Frame::new(
+                        BlockTy::Block(Vec::new()),
+                        result(wasm::block_ty_to_option(*ty)),
+                        stack.len(),
+                    ))
+                }
                 If(ty) => {
-                    let cond = stack.pop().unwrap();
-                    blocks.push(BlockTy::If(wasm::block_ty_to_option(*ty), Box::new(cond), Vec::new()));
+                    let cond = pop!();
+                    spill!();
+                    blocks.push(Frame::new(
+                        BlockTy::If(Box::new(cond), Vec::new()),
+                        result(wasm::block_ty_to_option(*ty)),
+                        stack.len(),
+                    ));
+                }
+                Else => {
+                    let mut frame = blocks.pop().unwrap();
+                    frame.set_result(&mut stack);
+                    frame.block = match frame.block {
+                        BlockTy::If(cond, v) => BlockTy::Else(cond, v, Vec::new()),
+                        _ => invalid!("else without if"),
+                    };
+                    frame.unreachable = false;
+                    blocks.push(frame);
                 }
-                Else => match blocks.pop().unwrap() {
-                    BlockTy::If(ty, cond, mut v) => {
-                        if ty.is_some() { v.push(stack.pop().unwrap()); }
-                        blocks.push(BlockTy::Else(ty, cond, v, Vec::new()))
-                    },
-                    _ => panic!("Else without if"),
-                },
                 End => {
                     if blocks.len() <= 1 {
                         break;
                     } else {
-                        let mut b = blocks.pop().unwrap();
-
-                        if b.ty().is_some() {
-                            b.push(stack.pop().unwrap());
-                            stack.push(b.op());
-                        } else {
-                            blocks.last_mut().unwrap().push(b.op());
+                        let mut frame = blocks.pop().unwrap();
+                        frame.set_result(&mut stack);
+                        blocks.last_mut().unwrap().push(frame.block.op());
+                        if let Some(l) = frame.result {
+                            stack.push(Direct::GetLocal(l));
                         }
                     }
                 }
-                Return => blocks.last_mut().unwrap().push(Direct::Return),
-                x => panic!("Instruction {} not supported", x),
+                Return => {
+                    if ret.is_some() {
+                        // Returning a value is the same as branching out of the function body
+                        let l = blocks[0].result.unwrap();
+                        let val = pop!();
+                        spill!();
+                        let depth = blocks.len() as u32 - 1;
+                        blocks
+                            .last_mut()
+                            .unwrap()
+                            .push(Direct::Seq(vec![Direct::SetLocal(l, Box::new(val)), Direct::Br(depth)]));
+                    } else {
+                        spill!();
+                        blocks.last_mut().unwrap().push(Direct::Return)
+                    }
+                    diverge!();
+                }
             }
         }
 
-        assert_eq!(blocks.len(), 1);
-
-        let mut body = blocks.pop().unwrap().op();
+        offset = code.elements().len();
+        if blocks.len() != 1 {
+            invalid!("the function body ends inside a block");
+        }
 
-        if ret.is_none() {
-            assert_eq!(stack.len(), 0, "Stuff left on stack: {:?}", stack);
-        } else {
-            assert_eq!(
-                stack.len(),
-                1,
-                "Wrong number of things on stack: {:?}",
-                stack
-            );
-            body = Direct::Seq(Box::new(body), Box::new(stack.pop().unwrap()));
+        let mut frame = blocks.pop().unwrap();
+        frame.set_result(&mut stack);
+        if !stack.is_empty() {
+            invalid!("values left on the stack at the end of the function");
         }
 
-        funs.push(Fun {
-            params,
-            body,
-            ty: ret,
-        });
+        let body = match frame.result {
+            Some(l) => Direct::Seq(vec![frame.block.op(), Direct::GetLocal(l)]),
+            None => frame.block.op(),
+        };
+
+        Ok((
+            Fun {
+                params,
+                body,
+                ty: ret,
+            },
+            nlocals.get(),
+        ))
     }
-    funs
 }
@@ -4,6 +4,8 @@ use wasm_vk::*;
 struct Args {
     short: Vec<char>,
     long: Vec<String>,
+    /// A subcommand like `check`, which comes before the file names
+    command: Option<String>,
     /// Stored backwards
     rest: Vec<String>,
 }
@@ -23,26 +25,49 @@ fn args() -> Args {
     let mut rest = Vec::new();
     // Skip the executable name
     args.next();
+    // Everything after `--` is a file name, even if it looks like an option or a subcommand
+    let mut options = true;
+    // Whether the first file name came before `--`, so it could be a subcommand
+    let mut could_be_command = false;
     for arg in args {
-        if arg.starts_with("--") {
+        if options && arg == "--" {
+            options = false;
+        } else if options && arg.starts_with("--") {
             long.push(arg[2..].to_owned());
-        } else if arg.starts_with('-') {
+        } else if options && arg.starts_with('-') {
             for i in arg[1..].chars() {
                 short.push(i);
             }
         } else {
+            if rest.is_empty() {
+                could_be_command = options;
+            }
             rest.push(arg);
         }
     }
+    // `check` is only a subcommand if there's an input file after it, so a file called `check` can still be compiled
+    let command = if could_be_command && rest.len() > 1 && rest[0] == "check" {
+        Some(rest.remove(0))
+    } else {
+        None
+    };
     rest.reverse();
-    Args { short, long, rest }
+    Args {
+        short,
+        long,
+        command,
+        rest,
+    }
 }
 
 fn help() {
     println!(
         r#"wasm-vk [options] <input.wasm> [output.spv]
+wasm-vk check [options] <input.wasm>
 
 If no output file is given, it will default to 'out.spv'.
+'check' lists everything in the module that wasm-vk can't compile, without compiling it.
+Anything after '--' is a file name, so 'wasm-vk -- check out.spv' compiles a file called 'check'.
 
 Options:
   -v, --verbose       Show more output, including dissasembled SPIR-V
@@ -60,6 +85,7 @@ fn main() {
     }
 
     let verbose = args.flag('v', "verbose");
+    let check = args.command.as_ref().map_or(false, |x| x == "check");
     let in_file = args.next().unwrap_or_else(|| {
         println!("No input file given\n");
         help();
@@ -67,23 +93,40 @@ fn main() {
     });
     let out_file = args.next().unwrap_or_else(|| String::from("out.spv"));
 
-    if verbose {
+    // There's no output file when checking
+    if verbose && !check {
         println!(
             "Deserializing WASM file {} to SPIR-V file {}",
             in_file, out_file
         );
     }
 
-    let w = wasm::deserialize_file(in_file)
+    let w = wasm::deserialize_file(&in_file)
         .expect("Deserialization error: are you sure this is valid WASM?");
 
     if verbose {
         println!("Deserialized WASM: {:?}", w);
     }
 
+    if check {
+        let problems = check::module(&w);
+        for p in &problems {
+            println!("{}", p);
+        }
+        if !problems.is_empty() {
+            println!("Found {} problems in {}", problems.len(), in_file);
+            std::process::exit(1)
+        }
+        println!("wasm-vk can compile everything in {}", in_file);
+        return;
+    }
+
     // First, we generate SPIR-V
     let ctx = spirv::Ctx::new();
-    let m = ctx.module(&w);
+    let m = ctx.module(&w).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
+    });
     let spv = spirv::module_bytes(m);
 
     if verbose {
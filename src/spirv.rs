@@ -21,6 +21,9 @@ struct Types {
 
 use std::collections::HashMap;
 
+/// The size of the emulated heap in u32s (so it's 128 bytes)
+const HEAP_WORDS: u32 = 32;
+
 #[derive(Debug, Copy, Clone)]
 struct Loop {
     head: u32,
@@ -72,12 +75,16 @@ pub struct Ctx {
     globals: IndexMap<SGlobal>,
     b: dr::Builder,
     heap: u32,
+    /// The variables holding passive data segments, by segment index
+    segments: HashMap<u32, u32>,
     /// (The SPIR-V variable, whether this has been set yet)
     heap_offset: (u32, bool),
     /// (The function, the function setting offset, the code)
     funs: Vec<Fun>,
     loops: Vec<Loop>,
     ext: u32,
+    /// The values of imported globals other than 'spv.id', by "module.field" name
+    imported_globals: HashMap<String, ir::Const>,
 }
 
 impl Default for spirv::Ctx {
@@ -106,11 +113,13 @@ impl Ctx {
             locals: IndexMap::default(),
             globals: IndexMap::with_capacity(1),
             heap: 0,
+            segments: HashMap::new(),
             heap_offset: (0, false),
             b,
             funs: Vec::new(),
             loops: Vec::new(),
             ext,
+            imported_globals: default_imported_globals(),
         };
 
         let t_uint = c.get(wasm::ValueType::I32);
@@ -132,17 +141,30 @@ impl Ctx {
         }
     }
 
-    pub fn module(mut self, m: &wasm::Module) -> dr::Module {
-        self.imports(m);
+    /// Provides an immutable global the module can import, like "env.__memory_base".
+    /// Position-independent code uses `env.__memory_base` and `env.__table_base`, which are 0 unless they're set here.
+    pub fn with_global(mut self, name: &str, value: ir::Const) -> Self {
+        self.imported_globals.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn module(self, m: &wasm::Module) -> Result<dr::Module, CompileError> {
+        // Code generation recurses into the IR like lowering does, so it needs the same stack
+        ir::with_stack(m, || self.compile(m))
+    }
+
+    fn compile(mut self, m: &wasm::Module) -> Result<dr::Module, CompileError> {
+        self.imports(m)?;
         let set_offset = !self.heap_offset.1;
-        let base = ir::to_base(m);
+        let base = ir::to_base(m)?;
 
         for f in base {
             let ret_ty = f.ty.map_or(self.void(), |x| self.get(x));
             // TODO what if this function calls another function that sets offset?
             let could_set_offset = f.body.fold(false, &|acc, x| match x {
-                ir::Base::Store(_, _, _) => true,
-                ir::Base::Load(_, _) => true,
+                ir::Base::Store(_, _, _, _) => true,
+                ir::Base::Load(_, _, _) => true,
+                ir::Base::Bulk(_, _, _, _) => true,
                 _ => acc,
             });
             let fun = self.id();
@@ -189,68 +211,159 @@ impl Ctx {
 
     /// Resolve imports from the module. Make sure to call this before `Ctx::fun()`
     /// Also handles heap allocation if necessary
-    pub fn imports(&mut self, m: &wasm::Module) {
+    pub fn imports(&mut self, m: &wasm::Module) -> Result<(), CompileError> {
+        let mut bufs = HashMap::new();
+
+        let mut global_idx = 0;
+        // The initial values of globals, so other globals and data segment offsets can use them
+        let mut inits = HashMap::new();
+
+        for i in m.import_section().into_iter().flat_map(|x| x.entries()) {
+            match import(m, i, &self.imported_globals)? {
+                Import::ThreadId => {
+                    self.globals.insert(global_idx, SGlobal::ThreadId);
+
+                    global_idx += 1;
+                }
+                Import::Global(c) => {
+                    self.user_global(global_idx, c.clone());
+                    inits.insert(global_idx, c);
+                    global_idx += 1;
+                }
+                Import::Buffer {
+                    set,
+                    binding,
+                    ty: elem_ty,
+                    load,
+                } => {
+                    let buf = if let Some(x) = bufs.get(&(set, binding)) {
+                        *x
+                    } else {
+                        let t_elem = self.get(elem_ty);
+                        let t_arr = self.type_runtime_array(t_elem);
+                        let t_struct = self.type_struct([t_arr]);
+                        let t_ptr =
+                            self.type_pointer(None, spvh::StorageClass::Uniform, t_struct);
+                        let buffer =
+                            self.variable(t_ptr, None, spvh::StorageClass::Uniform, None);
+
+                        // This is deprecated past SPIR-V 1.3, and should be replaced with the StorageBuffer StorageClass.
+                        // I don't know that any Vulkan implementations actually support that yet, though, so this works for now.
+                        self.decorate(t_struct, spvh::Decoration::BufferBlock, []);
+
+                        self.decorate(
+                            buffer,
+                            spvh::Decoration::DescriptorSet,
+                            [dr::Operand::LiteralInt32(set)],
+                        );
+                        self.decorate(
+                            buffer,
+                            spvh::Decoration::Binding,
+                            [dr::Operand::LiteralInt32(binding)],
+                        );
+
+                        self.decorate(
+                            t_arr,
+                            spvh::Decoration::ArrayStride,
+                            [dr::Operand::LiteralInt32(byte_size(elem_ty))],
+                        );
+                        self.member_decorate(
+                            t_struct,
+                            0,
+                            spvh::Decoration::Offset,
+                            [dr::Operand::LiteralInt32(0)],
+                        );
+
+                        bufs.insert((set, binding), buffer);
+
+                        buffer
+                    };
+
+                    let f = if load {
+                        Fun::BufGet(elem_ty, buf)
+                    } else {
+                        Fun::BufSet(elem_ty, buf)
+                    };
+
+                    self.funs.push(f);
+                }
+            }
+        }
+
+        let globals = m.global_section().into_iter().flat_map(|x| x.entries());
+        for g in globals {
+            let thread_id = |i| matches!(self.globals.get(i), Some(SGlobal::ThreadId));
+            let init = match global_init(g, global_idx, thread_id, &inits)? {
+                // An immutable copy of the thread id can just be the thread id
+                Init::ThreadId => {
+                    self.globals.insert(global_idx, SGlobal::ThreadId);
+                    global_idx += 1;
+                    continue;
+                }
+                Init::Const(c) => c,
+            };
+            inits.insert(global_idx, init.clone());
+            self.user_global(global_idx, init);
+            global_idx += 1;
+        }
+
+        self.heap(m, &inits)
+    }
+
+    /// Makes a Private variable for a global that starts out as `init`
+    fn user_global(&mut self, idx: u32, init: ir::Const) {
+        let wty = init.ty();
+        let pty = self.ptr(wty, spvh::StorageClass::Private);
+        let init = self.constant(init);
+        let n = self.variable(pty, None, spvh::StorageClass::Private, Some(init));
+        self.globals.insert(idx, SGlobal::User(wty, n));
+    }
+
+    /// Allocates the heap if the module has a memory, with the active data segments in it.
+    /// `inits` has the values of the globals that segment offsets can use.
+    fn heap(
+        &mut self,
+        m: &wasm::Module,
+        inits: &HashMap<u32, ir::Const>,
+    ) -> Result<(), CompileError> {
         if m.memory_section().is_some() {
             let t_uint = self.get(wasm::ValueType::I32);
-            let c_32 = self.constant_u32(t_uint, 32); // 128 bytes
-            let t_arr = self.type_array(t_uint, c_32);
+            let c_len = self.constant_u32(t_uint, HEAP_WORDS);
+            let t_arr = self.type_array(t_uint, c_len);
             let c_0 = self.constant_u32(t_uint, 0);
-            let data: Vec<u32> = if let Some(section) = m.data_section() {
-                assert_eq!(
-                    section.entries().len(),
-                    1,
-                    "wasm-vk currently only supports one data segment!"
-                );
-                let e = &section.entries()[0];
-
-                let offset = match e.offset() {
-                    None => (std::i32::MAX, false),
-                    Some(i) => match i.code() {
-                        // If the data section has an offset, prefer that one
-                        [wasm::Instruction::I32Const(i), wasm::Instruction::End] => (*i, true),
-                        _ => panic!("wasm-vk doesn't currently support offset expressions other than i32.const! Got instructions {:?}", i.code()),
-                    }
-                };
-                let offset: (u32, _) = (
-                    unsafe { std::mem::transmute(offset.0) },
-                    offset.1,
-                );
+            // Passive segments aren't in memory at the start, they're only used by `memory.init`
+            let active: Vec<_> = m
+                .data_section()
+                .into_iter()
+                .flat_map(|x| x.entries())
+                .enumerate()
+                .filter(|(_, x)| !x.passive())
+                .collect();
+            let data: Vec<u32> = if !active.is_empty() {
+                // Where each segment goes in linear memory
+                let mut segments = Vec::new();
+                for (segment, e) in active {
+                    segments.push((segment, data_offset(segment, e, inits)?, e.value()));
+                }
+                let heap_start = heap_start(&segments)?;
+                self.heap_offset = (self.constant_u32(t_uint, heap_start), true);
+
+                // Merge the segments into one image of the heap, with zeros in the gaps.
+                // If they overlap, later segments overwrite earlier ones.
+                let mut bytes = vec![0u8; (HEAP_WORDS * 4) as usize];
+                for (_, o, v) in segments {
+                    let o = (o - heap_start) as usize;
+                    bytes[o..o + v.len()].copy_from_slice(v);
+                }
 
-                use std::convert::TryInto;
                 // We store the bytes as little-endian
-                let mut data: Vec<u32> = e
-                    .value()
+                bytes
                     .chunks(4)
-                    .map(|x| {
-                        u32::from_le_bytes(x.try_into().expect("Data section not a multiple of 4"))
-                    })
+                    .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
                     .map(|x| self.constant_u32(t_uint, x))
-                    .collect();
-                let l = data.len();
-                assert!(l <= 32, "Memory size must be <= 128 bytes");
-                // Round l up to the next even number
-                let l = if l % 2 == 0 {
-                    l
-                } else {
-                    data.push(c_0);
-                    l + 1
-                };
-                // The padding on either side
-                let n = (32 - l) / 2;
-
-                let offset = (
-                    // TODO if saturating_sub is less than n change the actual data
-                    // Right now it doesn't work
-                    self.constant_u32(t_uint, (offset.0).saturating_sub(4 * n as u32)),
-                    offset.1,
-                );
-                self.heap_offset = offset;
-
-                // The linear memory is always exactly 128 bytes
-                let padding = std::iter::repeat(c_0).take(n);
-                padding.clone().chain(data).chain(padding).collect()
+                    .collect()
             } else {
-                (0..32).map(|_| c_0).collect()
+                (0..HEAP_WORDS).map(|_| c_0).collect()
             };
 
             let t_uint_ptr = self.type_pointer(None, spvh::StorageClass::Private, t_uint);
@@ -269,121 +382,30 @@ impl Ctx {
             let mem = self.variable(t_arr_ptr, None, spvh::StorageClass::Private, Some(data));
 
             self.heap = mem;
-        }
-
-        let mut bufs = HashMap::new();
 
-        let mut global_idx = 0;
-
-        for i in m.import_section().into_iter().flat_map(|x| x.entries()) {
-            match i.external() {
-                wasm::External::Global(_) => {
-                    if i.module() == "spv" && i.field() == "id" {
-                        self.globals.insert(global_idx, SGlobal::ThreadId);
-                    } else {
-                        panic!("Error: import {:?}", i)
-                    }
-
-                    global_idx += 1;
-                }
-                wasm::External::Function(t) => {
-                    if i.module() == "spv" {
-                        let f = i.field();
-                        let mut f = f.split(':');
-                        if f.next() == Some("buffer") {
-                            let set: u32 = f.next().unwrap().parse().unwrap();
-                            let binding: u32 = f.next().unwrap().parse().unwrap();
-                            let wasm::Type::Function(t) =
-                                &m.type_section().unwrap().types()[*t as usize];
-
-                            let d = f.next().unwrap();
-
-                            let elem_ty = if d == "load" {
-                                t.return_type().unwrap()
-                            } else if d == "store" {
-                                t.params()[1]
-                            } else {
-                                panic!("Invalid buffer import! Valid suffixes are :load and :store")
-                            };
-
-                            let buf = if let Some(x) = bufs.get(&(set, binding)) {
-                                *x
-                            } else {
-                                let t_elem = self.get(elem_ty);
-                                let t_arr = self.type_runtime_array(t_elem);
-                                let t_struct = self.type_struct([t_arr]);
-                                let t_ptr =
-                                    self.type_pointer(None, spvh::StorageClass::Uniform, t_struct);
-                                let buffer =
-                                    self.variable(t_ptr, None, spvh::StorageClass::Uniform, None);
-
-                                // This is deprecated past SPIR-V 1.3, and should be replaced with the StorageBuffer StorageClass.
-                                // I don't know that any Vulkan implementations actually support that yet, though, so this works for now.
-                                self.decorate(t_struct, spvh::Decoration::BufferBlock, []);
-
-                                self.decorate(
-                                    buffer,
-                                    spvh::Decoration::DescriptorSet,
-                                    [dr::Operand::LiteralInt32(set)],
-                                );
-                                self.decorate(
-                                    buffer,
-                                    spvh::Decoration::Binding,
-                                    [dr::Operand::LiteralInt32(binding)],
-                                );
-
-                                self.decorate(
-                                    t_arr,
-                                    spvh::Decoration::ArrayStride,
-                                    [dr::Operand::LiteralInt32(4)],
-                                );
-                                self.member_decorate(
-                                    t_struct,
-                                    0,
-                                    spvh::Decoration::Offset,
-                                    [dr::Operand::LiteralInt32(0)],
-                                );
-
-                                bufs.insert((set, binding), buffer);
-
-                                buffer
-                            };
-
-                            let f = match &*d {
-                                "load" => Fun::BufGet(elem_ty, buf),
-                                "store" => Fun::BufSet(elem_ty, buf),
-                                _ => unreachable!(),
-                            };
-
-                            self.funs.push(f);
-                        }
-                    }
+            let segments = m.data_section().into_iter().flat_map(|x| x.entries());
+            for (i, e) in segments.enumerate() {
+                if !e.passive() {
+                    continue;
                 }
-                x => panic!("We don't support importing {:?}", x),
+                // Pad it to a whole number of words, and there has to be at least one
+                let mut bytes = e.value().to_vec();
+                bytes.resize(((bytes.len() + 3) / 4).max(1) * 4, 0);
+                let data: Vec<u32> = bytes
+                    .chunks(4)
+                    .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                    .map(|x| self.constant_u32(t_uint, x))
+                    .collect();
+                let c_len = self.constant_u32(t_uint, data.len() as u32);
+                let t_arr = self.type_array(t_uint, c_len);
+                let data = self.constant_composite(t_arr, data);
+                let t_arr_ptr = self.type_pointer(None, spvh::StorageClass::Private, t_arr);
+                let seg = self.variable(t_arr_ptr, None, spvh::StorageClass::Private, Some(data));
+                self.segments.insert(i as u32, seg);
             }
         }
 
-        let globals = m.global_section().into_iter().flat_map(|x| x.entries());
-        for g in globals {
-            let wty = g.global_type().content_type();
-            let pty = self.ptr(wty, spvh::StorageClass::Private);
-            let ty = self.get(wty);
-
-            let init = g.init_expr().code();
-            let init = match init {
-                [wasm::Instruction::I32Const(i), wasm::Instruction::End] => {
-                    self.constant_u32(ty, unsafe { std::mem::transmute(*i) })
-                }
-                x => panic!(
-                    "We only support i32.const in init expressions for now! Got {:?}",
-                    x
-                ),
-            };
-
-            let n = self.variable(pty, None, spvh::StorageClass::Private, Some(init));
-            self.globals.insert(global_idx, SGlobal::User(wty, n));
-            global_idx += 1;
-        }
+        Ok(())
     }
 
     /// Returns whether it did anything
@@ -472,8 +494,11 @@ impl Ctx {
         }
     }
 
-    pub fn finish(mut self, entry: Option<u32>, set_offset: bool) -> dr::Module {
+    pub fn finish(mut self, entry: Option<u32>, set_offset: bool) -> Result<dr::Module, CompileError> {
         if let Some(entry) = entry {
+            if entry as usize >= self.funs.len() {
+                return Err(CompileError::Start { fun: entry });
+            }
             match self.funs[entry as usize] {
                 Fun::Defined {
                     fun,
@@ -518,10 +543,827 @@ impl Ctx {
                     self.entry_point(spvh::ExecutionModel::GLCompute, fun, "main", [id]);
                     self.execution_mode(fun, spvh::ExecutionMode::LocalSize, [64, 1, 1]);
                 }
-                _ => panic!("Only user defined functions can be the start function"),
+                _ => return Err(CompileError::Start { fun: entry }),
             }
         }
-        self.b.module()
+        Ok(self.b.module())
+    }
+
+    /// Turns a byte pointer into linear memory into an index into the heap's u32 array.
+    /// If the heap offset hasn't been set yet, this sets it so the pointer is in the middle of the heap range.
+    fn heap_index(&mut self, ptr: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+
+        let offset = self.heap_offset;
+        let ptr = if !offset.1 {
+            self.heap_offset.1 = true;
+
+            // Set the new offset to the pointer minus 64 (half the heap size)
+            let c64 = self.constant_u32(uint, 64);
+            let new_offset = self.i_sub(uint, None, ptr, c64).unwrap();
+            let c0 = self.constant_u32(uint, 0);
+            let ext = self.ext;
+            let new_offset = self
+                .ext_inst(uint, None, ext, spvh::GLOp::SMax as u32, [new_offset, c0])
+                .unwrap();
+            // Keep it a multiple of 4, so bytes stay in the same position within their word
+            let mask = self.constant_u32(uint, !3);
+            let new_offset = self.bitwise_and(uint, None, new_offset, mask).unwrap();
+            self.store(offset.0, new_offset, None, []).unwrap();
+
+            // New pointer
+            self.i_sub(uint, None, ptr, new_offset).unwrap()
+        } else {
+            let offset = self.load(uint, None, offset.0, None, []).unwrap();
+            self.i_sub(uint, None, ptr, offset).unwrap()
+        };
+
+        // Divide by four because of the size of a u32
+        let c4 = self.constant_u32(uint, 4);
+        self.u_div(uint, None, ptr, c4).unwrap()
+    }
+
+    /// A pointer to the heap word at `idx`
+    fn heap_ptr(&mut self, idx: u32) -> u32 {
+        let ptr_ty = self.ptr(wasm::ValueType::I32, spvh::StorageClass::Private);
+        let heap = self.heap;
+        self.access_chain(ptr_ty, None, heap, [idx]).unwrap()
+    }
+
+    /// The index of the heap word after `idx`.
+    /// This is clamped to the end of the heap, since we sometimes load the next word without needing it.
+    fn next_heap_index(&mut self, idx: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let ext = self.ext;
+        let c1 = self.constant_u32(uint, 1);
+        let last = self.constant_u32(uint, HEAP_WORDS - 1);
+        let idx = self.i_add(uint, None, idx, c1).unwrap();
+        self.ext_inst(uint, None, ext, spvh::GLOp::UMin as u32, [idx, last])
+            .unwrap()
+    }
+
+    /// Loads 32 bits from the heap, starting `shift` bits into the word at `idx` if it's not aligned
+    fn load_heap_word(&mut self, idx: u32, shift: Option<u32>) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let ptr = self.heap_ptr(idx);
+        let lo = self.load(uint, None, ptr, None, []).unwrap();
+        let shift = match shift {
+            Some(shift) => shift,
+            None => return lo,
+        };
+
+        let next = self.next_heap_index(idx);
+        let ptr = self.heap_ptr(next);
+        let hi = self.load(uint, None, ptr, None, []).unwrap();
+
+        let lo = self.shift_right_logical(uint, None, lo, shift).unwrap();
+        let hi = self.shift_opposite(hi, shift, false);
+        self.bitwise_or(uint, None, lo, hi).unwrap()
+    }
+
+    /// Shifts `x` by `32 - shift` bits, right if `right` is true and left otherwise.
+    /// Shifting by 32 is undefined, so we shift by 1 and then `31 - shift`, which gives 0 if `shift` is 0.
+    fn shift_opposite(&mut self, x: u32, shift: u32, right: bool) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let c1 = self.constant_u32(uint, 1);
+        let c31 = self.constant_u32(uint, 31);
+        let amount = self.i_sub(uint, None, c31, shift).unwrap();
+        if right {
+            let x = self.shift_right_logical(uint, None, x, c1).unwrap();
+            self.shift_right_logical(uint, None, x, amount).unwrap()
+        } else {
+            let x = self.shift_left_logical(uint, None, x, c1).unwrap();
+            self.shift_left_logical(uint, None, x, amount).unwrap()
+        }
+    }
+
+    /// Stores the low `bits` bits of `val` starting `shift` bits into the heap word at `idx`, spilling over into the next word if needed.
+    /// The other bits of both words are preserved.
+    fn store_heap_bits(&mut self, idx: u32, shift: u32, val: u32, bits: u32) {
+        let uint = self.get(wasm::ValueType::I32);
+        let mask = self.constant_u32(uint, (u64::MAX >> (64 - bits)) as u32);
+
+        // The parts of `val` and the mask that end up in each word
+        let lo_mask = self.shift_left_logical(uint, None, mask, shift).unwrap();
+        let lo_val = self.shift_left_logical(uint, None, val, shift).unwrap();
+        let hi_mask = self.shift_opposite(mask, shift, true);
+        let hi_val = self.shift_opposite(val, shift, true);
+
+        let ptr_lo = self.heap_ptr(idx);
+        let next = self.next_heap_index(idx);
+        let ptr_hi = self.heap_ptr(next);
+        // Store the high word first, so if it was clamped to the same word as the low one, the low one wins
+        for (ptr, mask, val) in [(ptr_hi, hi_mask, hi_val), (ptr_lo, lo_mask, lo_val)].iter().copied() {
+            let old = self.load(uint, None, ptr, None, []).unwrap();
+            let keep = self.not(uint, None, mask).unwrap();
+            let old = self.bitwise_and(uint, None, old, keep).unwrap();
+            let val = self.bitwise_and(uint, None, val, mask).unwrap();
+            let new = self.bitwise_or(uint, None, old, val).unwrap();
+            self.store(ptr, new, None, []).unwrap();
+        }
+    }
+
+    fn constant(&mut self, c: ir::Const) -> u32 {
+        match c {
+            ir::Const::I32(i) => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.constant_u32(ty, i as u32)
+            }
+            ir::Const::F32(i) => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.constant_f32(ty, i)
+            }
+            ir::Const::I64(i) => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.constant_u64(ty, i as u64)
+            }
+            ir::Const::F64(i) => {
+                let ty = self.get(wasm::ValueType::F64);
+                self.constant_f64(ty, i)
+            }
+        }
+    }
+
+    /// Loads the byte at `ptr` in linear memory, zero-extended to 32 bits
+    fn load_byte(&mut self, ptr: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let idx = self.heap_index(ptr);
+        let word_ptr = self.heap_ptr(idx);
+        let word = self.load(uint, None, word_ptr, None, []).unwrap();
+        let shift = self.byte_shift(ptr);
+        let c8 = self.constant_u32(uint, 8);
+        self.bit_field_u_extract(uint, None, word, shift, c8).unwrap()
+    }
+
+    /// Stores the low byte of `val` at `ptr` in linear memory
+    fn store_byte(&mut self, ptr: u32, val: u32) {
+        let uint = self.get(wasm::ValueType::I32);
+        let idx = self.heap_index(ptr);
+        let word_ptr = self.heap_ptr(idx);
+        let old = self.load(uint, None, word_ptr, None, []).unwrap();
+        let shift = self.byte_shift(ptr);
+        let c8 = self.constant_u32(uint, 8);
+        let new = self.bit_field_insert(uint, None, old, val, shift, c8).unwrap();
+        self.store(word_ptr, new, None, []).unwrap();
+    }
+
+    /// Emits a loop that runs `body` with the index `i` for each `i` from 0 up to `len`
+    fn byte_loop(&mut self, len: u32, body: impl FnOnce(&mut Self, u32)) {
+        let uint = self.get(wasm::ValueType::I32);
+        let t_bool = self.bool();
+        let c0 = self.constant_u32(uint, 0);
+        let c1 = self.constant_u32(uint, 1);
+
+        let head = self.id();
+        let l_body = self.id();
+        let cont = self.id();
+        let end = self.id();
+        // The incremented index, which is defined in the continue block
+        let next = self.id();
+
+        let pre = self.current_block;
+        self.branch(head).unwrap();
+        self.begin_basic_block(Some(head)).unwrap();
+        let i = self.phi(uint, None, [(c0, pre), (next, cont)]).unwrap();
+        self.loop_merge(end, cont, spvh::LoopControl::NONE, [])
+            .unwrap();
+        let cond = self.u_less_than(t_bool, None, i, len).unwrap();
+        self.branch_conditional(cond, l_body, end, []).unwrap();
+
+        self.begin_basic_block(Some(l_body)).unwrap();
+        body(self, i);
+        self.branch(cont).unwrap();
+
+        self.begin_basic_block(Some(cont)).unwrap();
+        self.i_add(uint, Some(next), i, c1).unwrap();
+        self.branch(head).unwrap();
+
+        self.begin_basic_block(Some(end)).unwrap();
+    }
+
+    /// The bit offset of the byte at `ptr` within its heap word
+    fn byte_shift(&mut self, ptr: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let c3 = self.constant_u32(uint, 3);
+        let byte = self.bitwise_and(uint, None, ptr, c3).unwrap();
+        self.shift_left_logical(uint, None, byte, c3).unwrap()
+    }
+
+    /// Lowers an `INumOp`, with the same results as WASM
+    fn inumop(&mut self, w: ir::Width, op: ir::INumOp, a: u32, b: u32) -> u32 {
+        let ty = self.int(w);
+        match op {
+            ir::INumOp::Mul => self.i_mul(ty, None, a, b).unwrap(),
+            ir::INumOp::Add => self.i_add(ty, None, a, b).unwrap(),
+            ir::INumOp::Sub => self.i_sub(ty, None, a, b).unwrap(),
+            ir::INumOp::Shl => self.shift_left_logical(ty, None, a, b).unwrap(),
+            ir::INumOp::ShrS => self.shift_right_arithmetic(ty, None, a, b).unwrap(),
+            ir::INumOp::ShrU => self.shift_right_logical(ty, None, a, b).unwrap(),
+            ir::INumOp::DivU => self.u_div(ty, None, a, b).unwrap(),
+            ir::INumOp::DivS => self.s_div(ty, None, a, b).unwrap(),
+            // WASM's rem_s takes the sign of the dividend, like SRem
+            ir::INumOp::RemS => self.s_rem(ty, None, a, b).unwrap(),
+            ir::INumOp::RemU => self.u_mod(ty, None, a, b).unwrap(),
+            ir::INumOp::And => self.bitwise_and(ty, None, a, b).unwrap(),
+            ir::INumOp::Or => self.bitwise_or(ty, None, a, b).unwrap(),
+            ir::INumOp::Xor => self.bitwise_xor(ty, None, a, b).unwrap(),
+            ir::INumOp::Rotl | ir::INumOp::Rotr => {
+                // `a rotl b` is `(a << (b % width)) | (a >> (-b % width))`
+                // Both shift amounts are masked so we never shift by the full width
+                let mask = self.int_const(w, w.bits() as u64 - 1);
+                let c0 = self.int_const(w, 0);
+                let neg_b = self.i_sub(ty, None, c0, b).unwrap();
+                let b = self.bitwise_and(ty, None, b, mask).unwrap();
+                let neg_b = self.bitwise_and(ty, None, neg_b, mask).unwrap();
+                let (l, r) = if op == ir::INumOp::Rotl {
+                    (b, neg_b)
+                } else {
+                    (neg_b, b)
+                };
+                let l = self.shift_left_logical(ty, None, a, l).unwrap();
+                let r = self.shift_right_logical(ty, None, a, r).unwrap();
+                self.bitwise_or(ty, None, l, r).unwrap()
+            }
+        }
+    }
+
+    /// Lowers an `FNumOp`
+    fn fnumop(&mut self, w: ir::Width, op: ir::FNumOp, a: u32, b: u32) -> u32 {
+        let ty = self.float(w);
+        let ext = self.ext;
+        match op {
+            ir::FNumOp::Add => self.f_add(ty, None, a, b).unwrap(),
+            ir::FNumOp::Sub => self.f_sub(ty, None, a, b).unwrap(),
+            ir::FNumOp::Mul => self.f_mul(ty, None, a, b).unwrap(),
+            ir::FNumOp::Div => self.f_div(ty, None, a, b).unwrap(),
+            ir::FNumOp::Max => self
+                .ext_inst(ty, None, ext, spvh::GLOp::FMax as u32, [a, b])
+                .unwrap(),
+            ir::FNumOp::Min => self
+                .ext_inst(ty, None, ext, spvh::GLOp::FMin as u32, [a, b])
+                .unwrap(),
+            ir::FNumOp::Copysign => {
+                // Take the sign bit from b and everything else from a
+                let t_int = self.int(w);
+                let a = self.bitcast(t_int, None, a).unwrap();
+                let b = self.bitcast(t_int, None, b).unwrap();
+                let (sign, rest) = match w {
+                    ir::Width::W32 => (
+                        self.constant_u32(t_int, 0x8000_0000),
+                        self.constant_u32(t_int, 0x7fff_ffff),
+                    ),
+                    ir::Width::W64 => (
+                        self.constant_u64(t_int, 0x8000_0000_0000_0000),
+                        self.constant_u64(t_int, 0x7fff_ffff_ffff_ffff),
+                    ),
+                };
+                let a = self.bitwise_and(t_int, None, a, rest).unwrap();
+                let b = self.bitwise_and(t_int, None, b, sign).unwrap();
+                let r = self.bitwise_or(t_int, None, a, b).unwrap();
+                self.bitcast(ty, None, r).unwrap()
+            }
+        }
+    }
+
+    /// Lowers an `IUnOp`, with the same results as WASM
+    fn iunop(&mut self, w: ir::Width, op: ir::IUnOp, a: u32) -> u32 {
+        match w {
+            ir::Width::W32 => self.iunop32(&op, a),
+            ir::Width::W64 => {
+                // The GLSL bit instructions only work on 32-bit integers, so we do each half separately
+                let uint = self.get(wasm::ValueType::I32);
+                let ulong = self.get(wasm::ValueType::I64);
+                let lo = self.u_convert(uint, None, a).unwrap();
+                let c32 = self.constant_u64(ulong, 32);
+                let hi = self.shift_right_logical(ulong, None, a, c32).unwrap();
+                let hi = self.u_convert(uint, None, hi).unwrap();
+
+                let r = match op {
+                    ir::IUnOp::Popcnt => {
+                        let lo = self.iunop32(&op, lo);
+                        let hi = self.iunop32(&op, hi);
+                        self.i_add(uint, None, lo, hi).unwrap()
+                    }
+                    ir::IUnOp::Clz | ir::IUnOp::Ctz => {
+                        // For clz, if the high half is zero, we count all of it plus the leading zeros of the low half
+                        // ctz is the same but the other way around
+                        let (first, second) = if op == ir::IUnOp::Clz {
+                            (hi, lo)
+                        } else {
+                            (lo, hi)
+                        };
+                        let t_bool = self.bool();
+                        let c0 = self.constant_u32(uint, 0);
+                        let c32 = self.constant_u32(uint, 32);
+                        let first_zero = self.i_equal(t_bool, None, first, c0).unwrap();
+                        let first = self.iunop32(&op, first);
+                        let second = self.iunop32(&op, second);
+                        let second = self.i_add(uint, None, second, c32).unwrap();
+                        self.select(uint, None, first_zero, second, first).unwrap()
+                    }
+                };
+                self.u_convert(ulong, None, r).unwrap()
+            }
+        }
+    }
+
+    /// Lowers an `FUnOp`
+    fn funop(&mut self, w: ir::Width, op: ir::FUnOp, a: u32) -> u32 {
+        let ty = self.float(w);
+        let ext = self.ext;
+        match op {
+            ir::FUnOp::Sqrt => self
+                .ext_inst(ty, None, ext, spvh::GLOp::Sqrt as u32, [a])
+                .unwrap(),
+            ir::FUnOp::Abs => self
+                .ext_inst(ty, None, ext, spvh::GLOp::FAbs as u32, [a])
+                .unwrap(),
+            ir::FUnOp::Ceil => self
+                .ext_inst(ty, None, ext, spvh::GLOp::Ceil as u32, [a])
+                .unwrap(),
+            ir::FUnOp::Floor => self
+                .ext_inst(ty, None, ext, spvh::GLOp::Floor as u32, [a])
+                .unwrap(),
+            ir::FUnOp::Trunc => self
+                .ext_inst(ty, None, ext, spvh::GLOp::Trunc as u32, [a])
+                .unwrap(),
+            ir::FUnOp::Nearest => self
+                .ext_inst(ty, None, ext, spvh::GLOp::RoundEven as u32, [a])
+                .unwrap(),
+            ir::FUnOp::Neg => self.f_negate(ty, None, a).unwrap(),
+        }
+    }
+
+    /// Lowers a `CvtOp`
+    fn cvtop(&mut self, op: ir::CvtOp, a: u32) -> u32 {
+        match op {
+            ir::CvtOp::F32toI32S => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.convert_f_to_s(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32toF32S => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.convert_s_to_f(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F32toI32U => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.convert_f_to_u(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32toF32U => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.convert_u_to_f(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F32toI64S => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.convert_f_to_s(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F32toI64U => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.convert_f_to_u(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I64toF32S => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.convert_s_to_f(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I64toF32U => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.convert_u_to_f(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32toI64S => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.s_convert(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32toI64U => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.u_convert(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I64toI32 => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.u_convert(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F64toI32S => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.convert_f_to_s(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F64toI32U => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.convert_f_to_u(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F64toI64S => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.convert_f_to_s(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F64toI64U => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.convert_f_to_u(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32toF64S | ir::CvtOp::I64toF64S => {
+                let ty = self.get(wasm::ValueType::F64);
+                self.convert_s_to_f(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32toF64U | ir::CvtOp::I64toF64U => {
+                let ty = self.get(wasm::ValueType::F64);
+                self.convert_u_to_f(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F32toF64 => {
+                let ty = self.get(wasm::ValueType::F64);
+                self.f_convert(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F64toF32 => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.f_convert(ty, None, a).unwrap()
+            }
+            // Reinterprets are just bitcasts, since our integer types are the same width as the floats
+            ir::CvtOp::F32asI32 => {
+                let ty = self.get(wasm::ValueType::I32);
+                self.bitcast(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I32asF32 => {
+                let ty = self.get(wasm::ValueType::F32);
+                self.bitcast(ty, None, a).unwrap()
+            }
+            ir::CvtOp::F64asI64 => {
+                let ty = self.get(wasm::ValueType::I64);
+                self.bitcast(ty, None, a).unwrap()
+            }
+            ir::CvtOp::I64asF64 => {
+                let ty = self.get(wasm::ValueType::F64);
+                self.bitcast(ty, None, a).unwrap()
+            }
+            // Sign extension is a signed bitfield extract of the low bits
+            ir::CvtOp::I32Extend8S => self.sign_extend(ir::Width::W32, a, 8),
+            ir::CvtOp::I32Extend16S => self.sign_extend(ir::Width::W32, a, 16),
+            ir::CvtOp::I64Extend8S => self.sign_extend(ir::Width::W64, a, 8),
+            ir::CvtOp::I64Extend16S => self.sign_extend(ir::Width::W64, a, 16),
+            ir::CvtOp::I64Extend32S => self.sign_extend(ir::Width::W64, a, 32),
+            ir::CvtOp::F32toI32SSat => self.trunc_sat(a, ir::Width::W32, ir::Width::W32, true),
+            ir::CvtOp::F32toI32USat => self.trunc_sat(a, ir::Width::W32, ir::Width::W32, false),
+            ir::CvtOp::F64toI32SSat => self.trunc_sat(a, ir::Width::W64, ir::Width::W32, true),
+            ir::CvtOp::F64toI32USat => self.trunc_sat(a, ir::Width::W64, ir::Width::W32, false),
+            ir::CvtOp::F32toI64SSat => self.trunc_sat(a, ir::Width::W32, ir::Width::W64, true),
+            ir::CvtOp::F32toI64USat => self.trunc_sat(a, ir::Width::W32, ir::Width::W64, false),
+            ir::CvtOp::F64toI64SSat => self.trunc_sat(a, ir::Width::W64, ir::Width::W64, true),
+            ir::CvtOp::F64toI64USat => self.trunc_sat(a, ir::Width::W64, ir::Width::W64, false),
+        }
+    }
+
+    /// Lowers an `ICompOp` to an i32 that's 1 if it's true and 0 otherwise
+    fn icompop(&mut self, op: ir::ICompOp, a: u32, b: u32) -> u32 {
+        // Unlike WASM, SPIR-V has booleans
+        // So we convert them to integers immediately
+        // Comparisons always return an i32, even for 64-bit operands
+        let ty = self.get(wasm::ValueType::I32);
+        let t_bool = self.bool();
+
+        let b = match op {
+            ir::ICompOp::Eq => self.i_equal(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::NEq => self.i_not_equal(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::LeU => self.u_less_than_equal(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::GeU => self.u_greater_than_equal(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::LtU => self.u_less_than(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::GtU => self.u_greater_than(t_bool, None, a, b).unwrap(),
+
+            ir::ICompOp::LeS => self.s_less_than_equal(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::GeS => self.s_greater_than_equal(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::LtS => self.s_less_than(t_bool, None, a, b).unwrap(),
+            ir::ICompOp::GtS => self.s_greater_than(t_bool, None, a, b).unwrap(),
+        };
+
+        let zero = self.constant_u32(ty, 0);
+        let one = self.constant_u32(ty, 1);
+        self.select(ty, None, b, one, zero).unwrap()
+    }
+
+    /// Lowers an `FCompOp` to an i32 that's 1 if it's true and 0 otherwise
+    fn fcompop(&mut self, op: ir::FCompOp, a: u32, b: u32) -> u32 {
+        // Unlike WASM, SPIR-V has booleans
+        // So we convert them to integers immediately
+        let ty = self.get(wasm::ValueType::I32);
+        let t_bool = self.bool();
+
+        let b = match op {
+            ir::FCompOp::Eq => self.f_ord_equal(t_bool, None, a, b).unwrap(),
+            ir::FCompOp::NEq => self.f_ord_not_equal(t_bool, None, a, b).unwrap(),
+            ir::FCompOp::Le => self.f_ord_less_than_equal(t_bool, None, a, b).unwrap(),
+            ir::FCompOp::Ge => self.f_ord_greater_than_equal(t_bool, None, a, b).unwrap(),
+            ir::FCompOp::Lt => self.f_ord_less_than(t_bool, None, a, b).unwrap(),
+            ir::FCompOp::Gt => self.f_ord_greater_than(t_bool, None, a, b).unwrap(),
+        };
+
+        let zero = self.constant_u32(ty, 0);
+        let one = self.constant_u32(ty, 1);
+        self.select(ty, None, b, one, zero).unwrap()
+    }
+
+    /// A pointer to the element of the buffer `buf`, which holds `ty`s, at the byte offset `ptr`
+    fn buf_ptr(&mut self, ty: wasm::ValueType, buf: u32, ptr: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let c0 = self.constant_u32(uint, 0);
+
+        let ptr_ty = self.ptr(ty, spvh::StorageClass::Uniform);
+        // Divide by the size of an element to get an index
+        let c_size = self.constant_u32(uint, byte_size(ty));
+        let ptr = self.u_div(uint, None, ptr, c_size).unwrap();
+
+        self.access_chain(ptr_ty, None, buf, [c0, ptr]).unwrap()
+    }
+
+    /// Loads a value of type `ty` from linear memory at `byte_ptr`
+    fn load_mem(&mut self, ty: wasm::ValueType, arg: ir::MemArg, byte_ptr: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+
+        let idx = self.heap_index(byte_ptr);
+        let aligned = mem_aligned(ty, arg);
+        // If we don't know it's aligned, we need to shift the value out of two words
+        let shift = if aligned {
+            None
+        } else {
+            Some(self.byte_shift(byte_ptr))
+        };
+        let lo = self.load_heap_word(idx, shift);
+
+        if let Some(bits) = arg.size.bits() {
+            let val = if bits == 32 {
+                lo
+            } else {
+                // An aligned narrow load is entirely within the word, and otherwise `load_heap_word` already shifted it down
+                let offset = if aligned {
+                    self.byte_shift(byte_ptr)
+                } else {
+                    self.constant_u32(uint, 0)
+                };
+                let count = self.constant_u32(uint, bits);
+                if arg.size.signed() {
+                    self.bit_field_s_extract(uint, None, lo, offset, count)
+                        .unwrap()
+                } else {
+                    self.bit_field_u_extract(uint, None, lo, offset, count)
+                        .unwrap()
+                }
+            };
+            return match ty {
+                wasm::ValueType::I64 => {
+                    let ulong = self.get(wasm::ValueType::I64);
+                    if arg.size.signed() {
+                        self.s_convert(ulong, None, val).unwrap()
+                    } else {
+                        self.u_convert(ulong, None, val).unwrap()
+                    }
+                }
+                _ => val,
+            };
+        }
+        match ty {
+            wasm::ValueType::I32 => lo,
+            wasm::ValueType::F32 => {
+                let t_float = self.get(wasm::ValueType::F32);
+                self.bitcast(t_float, None, lo).unwrap()
+            }
+            wasm::ValueType::I64 | wasm::ValueType::F64 => {
+                // The heap is made of u32s, so we combine two of them, little-endian
+                let c1 = self.constant_u32(uint, 1);
+                let idx = self.i_add(uint, None, idx, c1).unwrap();
+                let hi = self.load_heap_word(idx, shift);
+
+                let ulong = self.get(wasm::ValueType::I64);
+                let lo = self.u_convert(ulong, None, lo).unwrap();
+                let hi = self.u_convert(ulong, None, hi).unwrap();
+                let c32 = self.constant_u64(ulong, 32);
+                let hi = self.shift_left_logical(ulong, None, hi, c32).unwrap();
+                let val = self.bitwise_or(ulong, None, hi, lo).unwrap();
+                if ty == wasm::ValueType::F64 {
+                    let t_double = self.get(wasm::ValueType::F64);
+                    self.bitcast(t_double, None, val).unwrap()
+                } else {
+                    val
+                }
+            }
+        }
+    }
+
+    /// Stores `val`, of type `ty`, to linear memory at `byte_ptr`
+    fn store_mem(&mut self, ty: wasm::ValueType, arg: ir::MemArg, byte_ptr: u32, val: u32) {
+        let uint = self.get(wasm::ValueType::I32);
+
+        let idx = self.heap_index(byte_ptr);
+        let aligned = mem_aligned(ty, arg);
+
+        if let Some(bits) = arg.size.bits() {
+            let val = if ty == wasm::ValueType::I64 {
+                self.u_convert(uint, None, val).unwrap()
+            } else {
+                val
+            };
+            let shift = self.byte_shift(byte_ptr);
+            if aligned {
+                // Narrow stores replace their bits in the word and leave the rest alone
+                let ptr = self.heap_ptr(idx);
+                let val = if bits == 32 {
+                    val
+                } else {
+                    let old = self.load(uint, None, ptr, None, []).unwrap();
+                    let count = self.constant_u32(uint, bits);
+                    self.bit_field_insert(uint, None, old, val, shift, count)
+                        .unwrap()
+                };
+                self.store(ptr, val, None, []).unwrap();
+            } else {
+                self.store_heap_bits(idx, shift, val, bits);
+            }
+            return;
+        }
+        // Split 64-bit values into two u32s, little-endian
+        let words = match ty {
+            wasm::ValueType::I32 => vec![val],
+            wasm::ValueType::F32 => vec![self.bitcast(uint, None, val).unwrap()],
+            wasm::ValueType::I64 | wasm::ValueType::F64 => {
+                let ulong = self.get(wasm::ValueType::I64);
+                let val = if ty == wasm::ValueType::F64 {
+                    self.bitcast(ulong, None, val).unwrap()
+                } else {
+                    val
+                };
+                let c32 = self.constant_u64(ulong, 32);
+                let hi = self.shift_right_logical(ulong, None, val, c32).unwrap();
+                let hi = self.u_convert(uint, None, hi).unwrap();
+                let lo = self.u_convert(uint, None, val).unwrap();
+                vec![lo, hi]
+            }
+        };
+        let shift = if aligned {
+            None
+        } else {
+            Some(self.byte_shift(byte_ptr))
+        };
+        let mut idx = idx;
+        for (i, word) in words.into_iter().enumerate() {
+            if i != 0 {
+                let c1 = self.constant_u32(uint, 1);
+                idx = self.i_add(uint, None, idx, c1).unwrap();
+            }
+            match shift {
+                None => {
+                    let ptr = self.heap_ptr(idx);
+                    self.store(ptr, word, None, []).unwrap();
+                }
+                Some(shift) => self.store_heap_bits(idx, shift, word, 32),
+            }
+        }
+    }
+
+    /// Lowers a bulk memory operation to a loop over the bytes
+    fn bulk(&mut self, op: ir::BulkOp, dest: u32, src: u32, len: u32) {
+        let uint = self.get(wasm::ValueType::I32);
+        // If the heap offset hasn't been set, set it here based on `dest`, so it doesn't get set inside the loop
+        self.heap_index(dest);
+
+        match op {
+            ir::BulkOp::Fill => self.byte_loop(len, |ctx, i| {
+                let ptr = ctx.i_add(uint, None, dest, i).unwrap();
+                ctx.store_byte(ptr, src);
+            }),
+            ir::BulkOp::Copy => {
+                // The ranges can overlap, so if we're copying to a higher address we go backwards
+                let t_bool = self.bool();
+                let backwards = self.u_greater_than(t_bool, None, dest, src).unwrap();
+                self.byte_loop(len, |ctx, i| {
+                    let c1 = ctx.constant_u32(uint, 1);
+                    let last = ctx.i_sub(uint, None, len, c1).unwrap();
+                    let rev = ctx.i_sub(uint, None, last, i).unwrap();
+                    let i = ctx.select(uint, None, backwards, rev, i).unwrap();
+                    let from = ctx.i_add(uint, None, src, i).unwrap();
+                    let to = ctx.i_add(uint, None, dest, i).unwrap();
+                    let val = ctx.load_byte(from);
+                    ctx.store_byte(to, val);
+                })
+            }
+            ir::BulkOp::Init(seg) => {
+                let seg = *self
+                    .segments
+                    .get(&seg)
+                    .expect("the frontend only allows memory.init of a passive segment in a module with a memory");
+                self.byte_loop(len, |ctx, i| {
+                    let from = ctx.i_add(uint, None, src, i).unwrap();
+                    let c2 = ctx.constant_u32(uint, 2);
+                    let idx = ctx.shift_right_logical(uint, None, from, c2).unwrap();
+                    let ptr_ty = ctx.ptr(wasm::ValueType::I32, spvh::StorageClass::Private);
+                    let ptr = ctx.access_chain(ptr_ty, None, seg, [idx]).unwrap();
+                    let word = ctx.load(uint, None, ptr, None, []).unwrap();
+                    let shift = ctx.byte_shift(from);
+                    let c8 = ctx.constant_u32(uint, 8);
+                    let val = ctx.bit_field_u_extract(uint, None, word, shift, c8).unwrap();
+                    let to = ctx.i_add(uint, None, dest, i).unwrap();
+                    ctx.store_byte(to, val);
+                })
+            }
+        }
+    }
+
+    /// Lowers an `IUnOp` on a 32-bit integer, with the same results as WASM
+    fn iunop32(&mut self, op: &ir::IUnOp, x: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let ext = self.ext;
+        match op {
+            ir::IUnOp::Popcnt => self.bit_count(uint, None, x).unwrap(),
+            ir::IUnOp::Clz => {
+                // FindUMsb returns -1 for 0, so this gives 32 for 0 like WASM wants
+                let msb = self
+                    .ext_inst(uint, None, ext, spvh::GLOp::FindUMsb as u32, [x])
+                    .unwrap();
+                let c31 = self.constant_u32(uint, 31);
+                self.i_sub(uint, None, c31, msb).unwrap()
+            }
+            ir::IUnOp::Ctz => {
+                // FindILsb returns -1 (so u32::MAX) for 0, and WASM wants 32
+                let lsb = self
+                    .ext_inst(uint, None, ext, spvh::GLOp::FindILsb as u32, [x])
+                    .unwrap();
+                let c32 = self.constant_u32(uint, 32);
+                self.ext_inst(uint, None, ext, spvh::GLOp::UMin as u32, [lsb, c32])
+                    .unwrap()
+            }
+        }
+    }
+
+    /// Sign-extends the low `bits` bits of `x`
+    fn sign_extend(&mut self, width: ir::Width, x: u32, bits: u32) -> u32 {
+        let uint = self.get(wasm::ValueType::I32);
+        let c0 = self.constant_u32(uint, 0);
+        let cbits = self.constant_u32(uint, bits);
+        match width {
+            ir::Width::W32 => self.bit_field_s_extract(uint, None, x, c0, cbits).unwrap(),
+            // Vulkan only allows 32-bit bit field instructions, so we extract from the low word and sign extend that
+            ir::Width::W64 => {
+                let ulong = self.get(wasm::ValueType::I64);
+                let lo = self.u_convert(uint, None, x).unwrap();
+                let lo = if bits < 32 {
+                    self.bit_field_s_extract(uint, None, lo, c0, cbits).unwrap()
+                } else {
+                    lo
+                };
+                self.s_convert(ulong, None, lo).unwrap()
+            }
+        }
+    }
+
+    /// Converts a float to an integer like WASM's `trunc_sat` instructions.
+    /// Out-of-range values clamp to the integer's limits and NaN becomes 0, where OpConvertFToS/U would be undefined.
+    fn trunc_sat(&mut self, x: u32, from: ir::Width, to: ir::Width, signed: bool) -> u32 {
+        let t_float = self.float(from);
+        let t_int = self.int(to);
+        let t_bool = self.bool();
+        let ext = self.ext;
+
+        // The bounds of the integer type are powers of two, so they're exactly representable as floats
+        let bits = to.bits() as i32;
+        let (min, max, int_max) = if signed {
+            (
+                -(2.0f64.powi(bits - 1)),
+                2.0f64.powi(bits - 1),
+                u64::MAX >> (65 - bits),
+            )
+        } else {
+            (0.0, 2.0f64.powi(bits), u64::MAX >> (64 - bits))
+        };
+        let (min, max, zero) = match from {
+            ir::Width::W32 => (
+                self.constant_f32(t_float, min as f32),
+                self.constant_f32(t_float, max as f32),
+                self.constant_f32(t_float, 0.0),
+            ),
+            ir::Width::W64 => (
+                self.constant_f64(t_float, min),
+                self.constant_f64(t_float, max),
+                self.constant_f64(t_float, 0.0),
+            ),
+        };
+        let int_max = self.int_const(to, int_max);
+
+        // Values at or above `max` are handled after the conversion, and NaN converts 0 instead
+        let too_big = self
+            .f_ord_greater_than_equal(t_bool, None, x, max)
+            .unwrap();
+        let nan = self.is_nan(t_bool, None, x).unwrap();
+        let bad = self.logical_or(t_bool, None, too_big, nan).unwrap();
+        let clamped = self
+            .ext_inst(t_float, None, ext, spvh::GLOp::FMax as u32, [x, min])
+            .unwrap();
+        let clamped = self.select(t_float, None, bad, zero, clamped).unwrap();
+        let r = if signed {
+            self.convert_f_to_s(t_int, None, clamped).unwrap()
+        } else {
+            self.convert_f_to_u(t_int, None, clamped).unwrap()
+        };
+        self.select(t_int, None, too_big, int_max, r).unwrap()
+    }
+
+    /// An integer constant of the given width
+    fn int_const(&mut self, width: ir::Width, x: u64) -> u32 {
+        let ty = self.int(width);
+        match width {
+            ir::Width::W32 => self.constant_u32(ty, x as u32),
+            ir::Width::W64 => self.constant_u64(ty, x),
+        }
     }
 
     fn bool(&mut self) -> u32 {
@@ -574,6 +1416,8 @@ impl Ctx {
                 if let Some(i) = self.tys.i_64 {
                     i
                 } else {
+                    // We only declare the capability if the module actually uses 64-bit integers
+                    self.capability(spvh::Capability::Int64);
                     let i = self.type_int(64, 0);
                     self.tys.i_64 = Some(i);
                     i
@@ -592,6 +1436,8 @@ impl Ctx {
                 if let Some(i) = self.tys.f_64 {
                     i
                 } else {
+                    // Like Int64, we only declare this if the module uses doubles
+                    self.capability(spvh::Capability::Float64);
                     let i = self.type_float(64);
                     self.tys.f_64 = Some(i);
                     i
@@ -616,9 +1462,11 @@ impl DerefMut for Ctx {
 impl ToSpirv for ir::Base {
     type Ctx = Ctx;
     type Value = u32;
+    /// This recurses for every level of nesting, so to keep its stack frame small,
+    /// most arms just generate their operands and leave the rest to a method on `Ctx`.
     fn spv(self, ctx: &mut Ctx) -> u32 {
         match self {
-            ir::Base::Call(i, mut params) => {
+            ir::Base::Call(i, _, mut params) => {
                 let offset_fun = ctx.id();
                 match &mut ctx.funs[i as usize] {
                     Fun::Defined {
@@ -648,19 +1496,9 @@ impl ToSpirv for ir::Base {
                         let ty = *ty;
                         let buf = *buf;
 
-                        let ptr = params.pop().unwrap();
-
-                        let uint = ctx.get(wasm::ValueType::I32);
-                        let c0 = ctx.constant_u32(uint, 0);
-
-                        let ptr_ty = ctx.ptr(ty, spvh::StorageClass::Uniform);
+                        let ptr = params.pop().unwrap().spv(ctx);
+                        let ptr = ctx.buf_ptr(ty, buf, ptr);
                         let ty = ctx.get(ty);
-                        let ptr = ptr.spv(ctx);
-                        // Divide by four because of the size of a u32
-                        let c4 = ctx.constant_u32(uint, 4);
-                        let ptr = ctx.u_div(uint, None, ptr, c4).unwrap();
-
-                        let ptr = ctx.access_chain(ptr_ty, None, buf, [c0, ptr]).unwrap();
                         ctx.load(ty, None, ptr, None, []).unwrap()
                     }
                     Fun::BufSet(ty, buf) => {
@@ -670,19 +1508,11 @@ impl ToSpirv for ir::Base {
                         let val = params.pop().unwrap();
                         let ptr = params.pop().unwrap();
 
-                        let uint = ctx.get(wasm::ValueType::I32);
-                        let c0 = ctx.constant_u32(uint, 0);
-
                         // The pointer is lower in the stack for the WASM store instruction, so it gets evaluated first.
                         let ptr = ptr.spv(ctx);
                         let val = val.spv(ctx);
 
-                        let ptr_ty = ctx.ptr(ty, spvh::StorageClass::Uniform);
-                        // Divide by four because of the size of a u32
-                        let c4 = ctx.constant_u32(uint, 4);
-                        let ptr = ctx.u_div(uint, None, ptr, c4).unwrap();
-
-                        let ptr = ctx.access_chain(ptr_ty, None, buf, [c0, ptr]).unwrap();
+                        let ptr = ctx.buf_ptr(ty, buf, ptr);
                         ctx.store(ptr, val, None, []).unwrap();
                         0
                     }
@@ -692,139 +1522,42 @@ impl ToSpirv for ir::Base {
             ir::Base::INumOp(w, op, a, b) => {
                 let a = a.spv(ctx);
                 let b = b.spv(ctx);
-                let ty = ctx.int(w);
-                match op {
-                    ir::INumOp::Mul => ctx.i_mul(ty, None, a, b).unwrap(),
-                    ir::INumOp::Add => ctx.i_add(ty, None, a, b).unwrap(),
-                    ir::INumOp::Sub => ctx.i_sub(ty, None, a, b).unwrap(),
-                    ir::INumOp::Shl => ctx.shift_left_logical(ty, None, a, b).unwrap(),
-                    ir::INumOp::ShrS => ctx.shift_right_arithmetic(ty, None, a, b).unwrap(),
-                    ir::INumOp::ShrU => ctx.shift_right_logical(ty, None, a, b).unwrap(),
-                    ir::INumOp::DivU => ctx.u_div(ty, None, a, b).unwrap(),
-                    ir::INumOp::DivS => ctx.s_div(ty, None, a, b).unwrap(),
-                    ir::INumOp::And => ctx.bitwise_and(ty, None, a, b).unwrap(),
-                    ir::INumOp::Or => ctx.bitwise_or(ty, None, a, b).unwrap(),
-                    ir::INumOp::Xor => ctx.bitwise_xor(ty, None, a, b).unwrap(),
-                }
+                ctx.inumop(w, op, a, b)
             }
             ir::Base::FNumOp(w, op, a, b) => {
                 let a = a.spv(ctx);
                 let b = b.spv(ctx);
-                let ty = ctx.float(w);
-                let ext = ctx.ext;
-                match op {
-                    ir::FNumOp::Add => ctx.f_add(ty, None, a, b).unwrap(),
-                    ir::FNumOp::Sub => ctx.f_sub(ty, None, a, b).unwrap(),
-                    ir::FNumOp::Mul => ctx.f_mul(ty, None, a, b).unwrap(),
-                    ir::FNumOp::Div => ctx.f_div(ty, None, a, b).unwrap(),
-                    ir::FNumOp::Max => ctx
-                        .ext_inst(ty, None, ext, spvh::GLOp::FMax as u32, [a, b])
-                        .unwrap(),
-                    ir::FNumOp::Min => ctx
-                        .ext_inst(ty, None, ext, spvh::GLOp::FMin as u32, [a, b])
-                        .unwrap(),
-                }
+                ctx.fnumop(w, op, a, b)
+            }
+            ir::Base::IUnOp(w, op, a) => {
+                let a = a.spv(ctx);
+                ctx.iunop(w, op, a)
             }
             ir::Base::FUnOp(w, op, a) => {
                 let a = a.spv(ctx);
-                let ty = ctx.float(w);
-                let ext = ctx.ext;
-                match op {
-                    ir::FUnOp::Sqrt => ctx
-                        .ext_inst(ty, None, ext, spvh::GLOp::Sqrt as u32, [a])
-                        .unwrap(),
-                    ir::FUnOp::Abs => ctx
-                        .ext_inst(ty, None, ext, spvh::GLOp::FAbs as u32, [a])
-                        .unwrap(),
-                    ir::FUnOp::Ceil => ctx
-                        .ext_inst(ty, None, ext, spvh::GLOp::Ceil as u32, [a])
-                        .unwrap(),
-                    ir::FUnOp::Floor => ctx
-                        .ext_inst(ty, None, ext, spvh::GLOp::Floor as u32, [a])
-                        .unwrap(),
-                    ir::FUnOp::Neg => ctx.f_negate(ty, None, a).unwrap(),
-                }
+                ctx.funop(w, op, a)
             }
             ir::Base::CvtOp(op, a) => {
                 let a = a.spv(ctx);
-                match op {
-                    ir::CvtOp::F32toI32S => {
-                        let ty = ctx.get(wasm::ValueType::I32);
-                        ctx.convert_f_to_s(ty, None, a).unwrap()
-                    }
-                    ir::CvtOp::I32toF32S => {
-                        let ty = ctx.get(wasm::ValueType::F32);
-                        ctx.convert_s_to_f(ty, None, a).unwrap()
-                    }
-                    ir::CvtOp::F32toI32U => {
-                        let ty = ctx.get(wasm::ValueType::I32);
-                        ctx.convert_f_to_u(ty, None, a).unwrap()
-                    }
-                    ir::CvtOp::I32toF32U => {
-                        let ty = ctx.get(wasm::ValueType::F32);
-                        ctx.convert_u_to_f(ty, None, a).unwrap()
-                    }
-                }
+                ctx.cvtop(op, a)
             }
-            ir::Base::ICompOp(w, op, a, b) => {
+            ir::Base::ICompOp(_w, op, a, b) => {
                 let a = a.spv(ctx);
                 let b = b.spv(ctx);
-                let ty = ctx.int(w);
-                // Unlike WASM, SPIR-V has booleans
-                // So we convert them to integers immediately
-                let t_bool = ctx.bool();
-
-                let b = match op {
-                    ir::ICompOp::Eq => ctx.i_equal(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::NEq => ctx.i_not_equal(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::LeU => ctx.u_less_than_equal(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::GeU => ctx.u_greater_than_equal(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::LtU => ctx.u_less_than(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::GtU => ctx.u_greater_than(t_bool, None, a, b).unwrap(),
-
-                    ir::ICompOp::LeS => ctx.s_less_than_equal(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::GeS => ctx.s_greater_than_equal(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::LtS => ctx.s_less_than(t_bool, None, a, b).unwrap(),
-                    ir::ICompOp::GtS => ctx.s_greater_than(t_bool, None, a, b).unwrap(),
-                };
-
-                let zero = ctx.constant_u32(ty, 0);
-                let one = ctx.constant_u32(ty, 1);
-                ctx.select(ty, None, b, one, zero).unwrap()
+                ctx.icompop(op, a, b)
             }
             ir::Base::FCompOp(_w, op, a, b) => {
                 let a = a.spv(ctx);
                 let b = b.spv(ctx);
-                // Unlike WASM, SPIR-V has booleans
-                // So we convert them to integers immediately
-                let ty = ctx.get(wasm::ValueType::I32);
-                let t_bool = ctx.bool();
-
-                let b = match op {
-                    ir::FCompOp::Eq => ctx.f_ord_equal(t_bool, None, a, b).unwrap(),
-                    ir::FCompOp::NEq => ctx.f_ord_not_equal(t_bool, None, a, b).unwrap(),
-                    ir::FCompOp::Le => ctx.f_ord_less_than_equal(t_bool, None, a, b).unwrap(),
-                    ir::FCompOp::Ge => ctx.f_ord_greater_than_equal(t_bool, None, a, b).unwrap(),
-                    ir::FCompOp::Lt => ctx.f_ord_less_than(t_bool, None, a, b).unwrap(),
-                    ir::FCompOp::Gt => ctx.f_ord_greater_than(t_bool, None, a, b).unwrap(),
-                };
-
-                let zero = ctx.constant_u32(ty, 0);
-                let one = ctx.constant_u32(ty, 1);
-                ctx.select(ty, None, b, one, zero).unwrap()
+                ctx.fcompop(op, a, b)
             }
-            ir::Base::Const(ir::Const::I32(i)) => {
-                let ty = ctx.get(wasm::ValueType::I32);
-                ctx.constant_u32(ty, unsafe { std::mem::transmute(i) })
-            }
-            ir::Base::Const(ir::Const::F32(i)) => {
-                let ty = ctx.get(wasm::ValueType::F32);
-                ctx.constant_f32(ty, i)
-            }
-            ir::Base::Const(_) => panic!("We currently don't support 64-bit constants"),
-            ir::Base::Seq(a, b) => {
-                a.spv(ctx);
-                b.spv(ctx)
+            ir::Base::Const(c) => ctx.constant(c),
+            ir::Base::Seq(v) => {
+                let mut r = 0;
+                for x in v {
+                    r = x.spv(ctx);
+                }
+                r
             }
             ir::Base::GetLocal(l) => {
                 let ty = ctx.get(l.ty);
@@ -847,87 +1580,40 @@ impl ToSpirv for ir::Base {
                     let val = val.spv(ctx);
                     ctx.store(l, val, None, []).unwrap();
                 } else {
-                    panic!("Can't set spv.id to a new value!");
+                    unreachable!("'spv.id' is immutable, and the frontend rejects global.set of immutable globals");
                 }
                 0
             }
-            ir::Base::Load(ty, ptr) => {
-                let uint = ctx.get(wasm::ValueType::I32);
-
-                let ptr_ty = ctx.ptr(ty, spvh::StorageClass::Private);
-                let ty = ctx.get(ty);
+            ir::Base::Load(ty, arg, ptr) => {
                 let ptr = ptr.spv(ctx);
-
-                // If it hasn't set yet, put this pointer in the middle of the heap range
-                let offset = ctx.heap_offset;
-                let ptr = if !offset.1 {
-                    ctx.heap_offset.1 = true;
-
-                    // Set the new offset to the pointer minus 64 (half the heap size)
-                    let c64 = ctx.constant_u32(uint, 64);
-                    let new_offset = ctx.i_sub(uint, None, ptr, c64).unwrap();
-                    let c0 = ctx.constant_u32(uint, 0);
-                    let ext = ctx.ext;
-                    let new_offset = ctx
-                        .ext_inst(uint, None, ext, spvh::GLOp::SMax as u32, [new_offset, c0])
-                        .unwrap();
-                    ctx.store(offset.0, new_offset, None, []).unwrap();
-
-                    // New pointer
-                    ctx.i_sub(uint, None, ptr, new_offset).unwrap()
-                } else {
-                    let offset = ctx.load(uint, None, offset.0, None, []).unwrap();
-                    ctx.i_sub(uint, None, ptr, offset).unwrap()
-                };
-
-                // Divide by four because of the size of a u32
-                let c4 = ctx.constant_u32(uint, 4);
-                let ptr = ctx.u_div(uint, None, ptr, c4).unwrap();
-
-                let heap = ctx.heap;
-                let ptr = ctx.access_chain(ptr_ty, None, heap, [ptr]).unwrap();
-                ctx.load(ty, None, ptr, None, []).unwrap()
+                ctx.load_mem(ty, arg, ptr)
             }
-            ir::Base::Store(ty, ptr, val) => {
-                let uint = ctx.get(wasm::ValueType::I32);
-
+            ir::Base::Store(ty, arg, ptr, val) => {
                 // The pointer is lower in the stack for the WASM store instruction, so it gets evaluated first.
                 let ptr = ptr.spv(ctx);
                 let val = val.spv(ctx);
-
-                let ptr_ty = ctx.ptr(ty, spvh::StorageClass::Private);
-
-                // If it hasn't set yet, put this pointer in the middle of the heap range
-                let offset = ctx.heap_offset;
-                let ptr = if !offset.1 {
-                    ctx.heap_offset.1 = true;
-
-                    // Set the new offset to the pointer minus 64 (half the heap size)
-                    let c64 = ctx.constant_u32(uint, 64);
-                    let new_offset = ctx.i_sub(uint, None, ptr, c64).unwrap();
-                    let c0 = ctx.constant_u32(uint, 0);
-                    let ext = ctx.ext;
-                    let new_offset = ctx
-                        .ext_inst(uint, None, ext, spvh::GLOp::SMax as u32, [new_offset, c0])
-                        .unwrap();
-                    ctx.store(offset.0, new_offset, None, []).unwrap();
-
-                    // New pointer
-                    ctx.i_sub(uint, None, ptr, new_offset).unwrap()
-                } else {
-                    let offset = ctx.load(uint, None, offset.0, None, []).unwrap();
-                    ctx.i_sub(uint, None, ptr, offset).unwrap()
-                };
-
-                // Divide by four because of the size of a u32
-                let c4 = ctx.constant_u32(uint, 4);
-                let ptr = ctx.u_div(uint, None, ptr, c4).unwrap();
-
-                let heap = ctx.heap;
-                let ptr = ctx.access_chain(ptr_ty, None, heap, [ptr]).unwrap();
-                ctx.store(ptr, val, None, []).unwrap();
+                ctx.store_mem(ty, arg, ptr, val);
                 0
             }
+            ir::Base::Bulk(op, dest, src, len) => {
+                let dest = dest.spv(ctx);
+                let src = src.spv(ctx);
+                let len = len.spv(ctx);
+                ctx.bulk(op, dest, src, len);
+                0
+            }
+            ir::Base::Select(ty, a, b, cond) => {
+                // They're evaluated in the same order as in WASM
+                let a = a.spv(ctx);
+                let b = b.spv(ctx);
+                let cond = cond.spv(ctx);
+                let t_uint = ctx.get(wasm::ValueType::I32);
+                let c0 = ctx.constant_u32(t_uint, 0);
+                let t_bool = ctx.bool();
+                let cond = ctx.i_not_equal(t_bool, None, cond, c0).unwrap();
+                let ty = ctx.get(ty);
+                ctx.select(ty, None, cond, a, b).unwrap()
+            }
             ir::Base::If { cond, ty, t, f } => {
                 let l_t = ctx.id();
                 let l_f = ctx.id();
@@ -962,6 +1648,33 @@ impl ToSpirv for ir::Base {
                     },
                 }
             }
+            ir::Base::Switch { sel, cases, default } => {
+                let l_d = ctx.id();
+                let l_m = ctx.id();
+                let labels: Vec<_> = cases.iter().map(|_| ctx.id()).collect();
+
+                let sel = sel.spv(ctx);
+                let targets: Vec<_> = cases
+                    .iter()
+                    .zip(&labels)
+                    .flat_map(|((vals, _), l)| vals.iter().map(move |v| (*v, *l)))
+                    .collect();
+
+                ctx.selection_merge(l_m, spvh::SelectionControl::NONE)
+                    .unwrap();
+                ctx.switch(sel, l_d, targets).unwrap();
+                for ((_, x), l) in cases.into_iter().zip(labels) {
+                    ctx.begin_basic_block(Some(l)).unwrap();
+                    x.spv(ctx);
+                    ctx.branch(l_m).unwrap();
+                }
+                ctx.begin_basic_block(Some(l_d)).unwrap();
+                default.spv(ctx);
+                ctx.branch(l_m).unwrap();
+                ctx.begin_basic_block(Some(l_m)).unwrap();
+
+                0
+            }
             ir::Base::Loop(a) => {
                 let head = ctx.id();
                 let cont = ctx.id();
@@ -1010,7 +1723,283 @@ impl ToSpirv for ir::Base {
                 ctx.begin_basic_block(None).unwrap();
                 0
             }
+            ir::Base::Unreachable => {
+                // Shaders can't trap, so we just tell the driver this never happens
+                ctx.unreachable().unwrap();
+                ctx.begin_basic_block(None).unwrap();
+                0
+            }
+        }
+    }
+}
+
+/// Evaluates a constant expression, like a global initializer or data segment offset.
+/// `global.get` only works if we know the global's value at compile time, which is in `globals`.
+fn const_expr(code: &[wasm::Instruction], globals: &HashMap<u32, ir::Const>) -> Option<ir::Const> {
+    match code {
+        [wasm::Instruction::I32Const(i), wasm::Instruction::End] => Some(ir::Const::I32(*i)),
+        [wasm::Instruction::I64Const(i), wasm::Instruction::End] => Some(ir::Const::I64(*i)),
+        [wasm::Instruction::F32Const(i), wasm::Instruction::End] => {
+            Some(ir::Const::F32(f32::from_bits(*i)))
+        }
+        [wasm::Instruction::F64Const(i), wasm::Instruction::End] => {
+            Some(ir::Const::F64(f64::from_bits(*i)))
+        }
+        [wasm::Instruction::GetGlobal(i), wasm::Instruction::End] => globals.get(i).cloned(),
+        _ => None,
+    }
+}
+
+/// The imported globals we provide without `Ctx::with_global`, which position-independent code uses
+fn default_imported_globals() -> HashMap<String, ir::Const> {
+    let mut globals = HashMap::new();
+    globals.insert("env.__memory_base".to_string(), ir::Const::I32(0));
+    globals.insert("env.__table_base".to_string(), ir::Const::I32(0));
+    globals
+}
+
+/// One of the imports we provide
+enum Import {
+    ThreadId,
+    /// A global with a value given to `Ctx::with_global`, or a default one
+    Global(ir::Const),
+    /// The load or store function for the buffer at `set` and `binding`, which has elements of type `ty`
+    Buffer {
+        set: u32,
+        binding: u32,
+        ty: wasm::ValueType,
+        load: bool,
+    },
+}
+
+/// Figures out which of our imports the module is asking for.
+/// `globals` has the values of the globals we provide besides 'spv.id'.
+fn import(
+    m: &wasm::Module,
+    i: &wasm::ImportEntry,
+    globals: &HashMap<String, ir::Const>,
+) -> Result<Import, CompileError> {
+    let name = format!("{}.{}", i.module(), i.field());
+    let err = |reason| CompileError::Import {
+        name: name.clone(),
+        reason,
+    };
+    match i.external() {
+        wasm::External::Global(g) if name == "spv.id" => {
+            if g.is_mutable() || g.content_type() != wasm::ValueType::I32 {
+                return Err(err("'spv.id' is an immutable i32"));
+            }
+            Ok(Import::ThreadId)
+        }
+        wasm::External::Global(g) => {
+            let c = globals.get(&name).ok_or_else(|| {
+                err("the only globals we provide are 'spv.id' and ones given to Ctx::with_global")
+            })?;
+            if g.is_mutable() {
+                return Err(err("imported globals need to be immutable"));
+            }
+            if g.content_type() != c.ty() {
+                return Err(err("the global's type doesn't match the value it was given"));
+            }
+            Ok(Import::Global(c.clone()))
+        }
+        wasm::External::Function(t) => {
+            let bad_name = "buffer imports are named 'buffer:<set>:<binding>:load' or 'buffer:<set>:<binding>:store'";
+            let parts: Vec<_> = i.field().split(':').collect();
+            let (set, binding, d) = match (i.module(), &parts[..]) {
+                ("spv", ["buffer", set, binding, d]) => {
+                    match (set.parse::<u32>(), binding.parse::<u32>()) {
+                        (Ok(set), Ok(binding)) => (set, binding, *d),
+                        _ => return Err(err(bad_name)),
+                    }
+                }
+                ("spv", ["buffer", ..]) => return Err(err(bad_name)),
+                _ => {
+                    return Err(err(
+                        "the only functions we provide are buffer loads and stores, like 'spv.buffer:0:0:load'",
+                    ))
+                }
+            };
+            let types = m.type_section().map_or(&[][..], |x| x.types());
+            let wasm::Type::Function(t) = match types.get(*t as usize) {
+                Some(t) => t,
+                None => return Err(err("its function type doesn't exist")),
+            };
+
+            let (ty, load) = match (d, t.params(), t.return_type()) {
+                ("load", [wasm::ValueType::I32], Some(ty)) => (ty, true),
+                ("store", [wasm::ValueType::I32, ty], None) => (*ty, false),
+                ("load", _, _) => return Err(err("buffer loads need the type (param i32) (result T)")),
+                ("store", _, _) => return Err(err("buffer stores need the type (param i32 T)")),
+                _ => return Err(err(bad_name)),
+            };
+            Ok(Import::Buffer {
+                set,
+                binding,
+                ty,
+                load,
+            })
+        }
+        wasm::External::Memory(_) => Err(err("linear memory needs to be defined in the module")),
+        wasm::External::Table(_) => Err(err("the function table needs to be defined in the module")),
+    }
+}
+
+/// Where an active data segment goes in linear memory.
+/// `globals` has the values of the globals its offset can get.
+/// The segment's end is checked to fit in 32 bits, so adding its length to the offset doesn't overflow.
+fn data_offset(
+    segment: usize,
+    e: &wasm::DataSegment,
+    globals: &HashMap<u32, ir::Const>,
+) -> Result<u32, CompileError> {
+    // Active segments always have an offset
+    let code = e.offset().as_ref().unwrap().code();
+    match const_expr(code, globals) {
+        // The offset is unsigned, even though it's an i32
+        Some(ir::Const::I32(i)) => match (i as u32).checked_add(e.value().len() as u32) {
+            Some(_) => Ok(i as u32),
+            None => Err(CompileError::Data {
+                segment,
+                reason: format!("it goes past the end of the address space, starting at {}", i as u32),
+            }),
+        },
+        _ => Err(CompileError::Data {
+            segment,
+            reason: format!("offsets need to be constant i32s, got {:?}", code),
+        }),
+    }
+}
+
+/// Where in linear memory our heap starts, so that it has all the active data segments.
+/// `segments` has the index, offset and contents of each one, and can't be empty.
+fn heap_start(segments: &[(usize, u32, &[u8])]) -> Result<u32, CompileError> {
+    // Keep the start a multiple of 4, so bytes stay in the same position within their word
+    let start = segments.iter().map(|(_, o, _)| *o).min().unwrap() & !3;
+    let (last, end) = segments
+        .iter()
+        .map(|(i, o, v)| (*i, o + v.len() as u32))
+        .max_by_key(|(_, end)| *end)
+        .unwrap();
+    let size = HEAP_WORDS * 4;
+    if end - start > size {
+        return Err(CompileError::Data {
+            segment: last,
+            reason: format!(
+                "the data segments span {} bytes, but memory must be <= {} bytes",
+                end - start,
+                size
+            ),
+        });
+    }
+
+    // Put the data in the middle of the heap, unless that would start it below address 0
+    let padding = ((size - (end - start)) / 2) & !3;
+    Ok(start.saturating_sub(padding))
+}
+
+/// What a global defined in the module starts out as
+enum Init {
+    ThreadId,
+    Const(ir::Const),
+}
+
+/// `thread_id` says whether a global is the thread id, and `inits` has the values of the constant globals before this one
+fn global_init(
+    g: &wasm::GlobalEntry,
+    idx: u32,
+    thread_id: impl Fn(u32) -> bool,
+    inits: &HashMap<u32, ir::Const>,
+) -> Result<Init, CompileError> {
+    match (g.init_expr().code(), g.global_type().is_mutable()) {
+        // An immutable copy of the thread id can just be the thread id
+        ([wasm::Instruction::GetGlobal(i), wasm::Instruction::End], false) if thread_id(*i) => {
+            Ok(Init::ThreadId)
+        }
+        (init, _) => const_expr(init, inits)
+            .map(Init::Const)
+            .ok_or(CompileError::GlobalInit { global: idx }),
+    }
+}
+
+/// Finds everything in the module's imports, data segments, globals and start function that `ctx` can't compile.
+/// Unlike `Ctx::module`, it keeps going after the first problem.
+pub(crate) fn check(ctx: &Ctx, m: &wasm::Module, errors: &mut Vec<CompileError>) {
+    let mut thread_ids = std::collections::HashSet::new();
+    let mut inits = HashMap::new();
+    let mut global_idx = 0;
+    for i in m.import_section().into_iter().flat_map(|x| x.entries()) {
+        match import(m, i, &ctx.imported_globals) {
+            Ok(Import::ThreadId) => {
+                thread_ids.insert(global_idx);
+            }
+            Ok(Import::Global(c)) => {
+                inits.insert(global_idx, c);
+            }
+            Ok(Import::Buffer { .. }) => (),
+            Err(e) => errors.push(e),
+        }
+        if let wasm::External::Global(_) = i.external() {
+            global_idx += 1;
+        }
+    }
+
+    for g in m.global_section().into_iter().flat_map(|x| x.entries()) {
+        match global_init(g, global_idx, |i| thread_ids.contains(&i), &inits) {
+            Ok(Init::ThreadId) => {
+                thread_ids.insert(global_idx);
+            }
+            Ok(Init::Const(c)) => {
+                inits.insert(global_idx, c);
+            }
+            Err(e) => errors.push(e),
         }
+        global_idx += 1;
+    }
+
+    if m.memory_section().is_some() {
+        let active = m
+            .data_section()
+            .into_iter()
+            .flat_map(|x| x.entries())
+            .enumerate()
+            .filter(|(_, x)| !x.passive());
+        let mut segments = Vec::new();
+        for (segment, e) in active {
+            match data_offset(segment, e, &inits) {
+                Ok(o) => segments.push((segment, o, e.value())),
+                Err(e) => errors.push(e),
+            }
+        }
+        if !segments.is_empty() {
+            if let Err(e) = heap_start(&segments) {
+                errors.push(e);
+            }
+        }
+    }
+
+    if let Some(fun) = m.start_section() {
+        let imported = m.import_count(wasm::ImportCountType::Function) as u32;
+        let defined = m.function_section().map_or(0, |x| x.entries().len()) as u32;
+        if fun < imported || fun >= imported + defined {
+            errors.push(CompileError::Start { fun });
+        }
+    }
+}
+
+/// Whether a load or store of `ty` is known to be aligned enough that it doesn't cross a heap word.
+/// That's 4-byte alignment, or the size of the access if it's smaller.
+fn mem_aligned(ty: wasm::ValueType, arg: ir::MemArg) -> bool {
+    let bytes = arg.size.bits().map_or(byte_size(ty), |b| b / 8);
+    // A malformed alignment hint can be too big to shift by, but then it claims to be aligned anyway
+    1u32.checked_shl(arg.align).map_or(true, |align| align >= bytes.min(4))
+}
+
+/// The size of a WASM value type in bytes
+fn byte_size(t: wasm::ValueType) -> u32 {
+    match t {
+        wasm::ValueType::I32 | wasm::ValueType::F32 => 4,
+        wasm::ValueType::I64 | wasm::ValueType::F64 => 8,
     }
 }
 
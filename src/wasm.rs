@@ -0,0 +1,169 @@
+pub use parity_wasm::elements::*;
+pub use parity_wasm::{serialize, serialize_to_file};
+
+pub fn block_ty_to_option(b: BlockType) -> Option<ValueType> {
+    match b {
+        BlockType::Value(v) => Some(v),
+        BlockType::NoResult => None,
+    }
+}
+
+/// Parses a module, like `parity_wasm::deserialize_buffer`, but reads bulk memory and `trunc_sat` instructions correctly.
+/// Use this or `deserialize_file` for modules you want to compile.
+///
+/// It records what it changed in a custom section, so if you serialize the module again, it won't be the same as the original.
+pub fn deserialize_buffer(bytes: &[u8]) -> Result<Module, Error> {
+    let mut bytes = bytes.to_vec();
+    let mut fixups = Vec::new();
+    fix_instructions(&mut bytes, &mut fixups);
+    let mut m: Module = parity_wasm::deserialize_buffer(&bytes)?;
+    if !fixups.is_empty() {
+        let payload = fixups
+            .iter()
+            .flat_map(|(body, idx, fixup)| vec![*body, *idx, *fixup as u32])
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+        m.sections_mut().push(Section::Custom(CustomSection::new(
+            FIXUPS.to_string(),
+            payload,
+        )));
+    }
+    Ok(m)
+}
+
+/// Parses a module from a file, like `parity_wasm::deserialize_file`, but reads bulk memory and `trunc_sat` instructions correctly
+pub fn deserialize_file<P: AsRef<std::path::Path>>(p: P) -> Result<Module, Error> {
+    let bytes = std::fs::read(p)
+        .map_err(|e| Error::HeapOther(format!("Can't read from the file: {:?}", e)))?;
+    deserialize_buffer(&bytes)
+}
+
+/// The custom section `deserialize_buffer` records its fixups in
+const FIXUPS: &str = "wasm-vk.fixups";
+
+/// Something `deserialize_buffer` changed about an instruction, so the frontend can undo it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fixup {
+    /// A `nop` that isn't in the original code, so it shouldn't count towards instruction offsets
+    Nop,
+    /// A `trunc_sat` conversion, which we parse as the trapping conversion with the same types
+    TruncSat,
+}
+
+/// The fixups in each function body, indexed by the position of the body in the code section.
+/// Each one has the index of the instruction it's at, in order.
+/// There aren't any if the module wasn't parsed with `deserialize_buffer`.
+pub(crate) fn fixups(m: &Module) -> Vec<Vec<(u32, Fixup)>> {
+    let mut bodies = Vec::new();
+    let section = match m.custom_sections().find(|x| x.name() == FIXUPS) {
+        Some(x) => x,
+        None => return bodies,
+    };
+    let words: Vec<_> = section
+        .payload()
+        .chunks(4)
+        .map(|x| {
+            let mut word = [0; 4];
+            word.copy_from_slice(x);
+            u32::from_le_bytes(word)
+        })
+        .collect();
+    for x in words.chunks(3) {
+        let fixup = match x[2] {
+            0 => Fixup::Nop,
+            1 => Fixup::TruncSat,
+            _ => continue,
+        };
+        let body = x[0] as usize;
+        if bodies.len() <= body {
+            bodies.resize(body + 1, Vec::new());
+        }
+        bodies[body].push((x[1], fixup));
+    }
+    bodies
+}
+
+/// parity-wasm reads some bulk memory instructions with an older encoding than the final proposal,
+/// so we change them to the encoding it expects before parsing:
+/// - `memory.init` and `table.init` have their reserved byte after the segment index, but it reads it before.
+///   So it reads the segment index as the reserved byte, and fails on any segment but 0.
+/// - `memory.copy` and `table.copy` have two reserved bytes, but it only reads one, and the other would become an `unreachable`.
+///   We make it a `nop` instead, and add a `Fixup::Nop` to `fixups` so it doesn't change the offsets of later instructions.
+/// - It doesn't know the saturating `trunc_sat` conversions at all.
+///   We change them to the trapping conversion with the same types followed by a `nop`, and add a `Fixup::TruncSat` for it.
+///
+/// The instructions stay the same size, so nothing else in the module needs to change.
+/// `fixups` gets the index of the function body, the index of the instruction in it, and the fixup.
+/// If the module doesn't parse, we stop and leave it to parity-wasm to report the error.
+fn fix_instructions(bytes: &mut [u8], fixups: &mut Vec<(u32, u32, Fixup)>) -> Option<()> {
+    use std::io::Cursor;
+
+    let original = bytes.to_vec();
+    let mut rdr = Cursor::new(&original[..]);
+    // Skip the magic number and version
+    rdr.set_position(8);
+    while (rdr.position() as usize) < original.len() {
+        let id = u8::from(Uint8::deserialize(&mut rdr).ok()?);
+        let size = u32::from(VarUint32::deserialize(&mut rdr).ok()?);
+        let end = rdr.position() + u64::from(size);
+        // Instructions are only in the code section
+        if id != 10 {
+            rdr.set_position(end);
+            continue;
+        }
+
+        let count = u32::from(VarUint32::deserialize(&mut rdr).ok()?);
+        for body in 0..count {
+            let size = u32::from(VarUint32::deserialize(&mut rdr).ok()?);
+            let body_end = rdr.position() + u64::from(size);
+            let nlocals = u32::from(VarUint32::deserialize(&mut rdr).ok()?);
+            for _ in 0..nlocals {
+                Local::deserialize(&mut rdr).ok()?;
+            }
+
+            // The index parity-wasm will give the next instruction
+            let mut idx = 0;
+            while rdr.position() < body_end {
+                let start = rdr.position() as usize;
+                match (original.get(start), original.get(start + 1)) {
+                    // memory.init and table.init: the segment index and then the reserved byte
+                    (Some(0xfc), Some(0x08)) | (Some(0xfc), Some(0x0c)) => {
+                        rdr.set_position(start as u64 + 2);
+                        let seg = rdr.position() as usize;
+                        VarUint32::deserialize(&mut rdr).ok()?;
+                        let reserved = rdr.position() as usize;
+                        bytes[seg] = *original.get(reserved)?;
+                        bytes[seg + 1..=reserved].copy_from_slice(&original[seg..reserved]);
+                        rdr.set_position(reserved as u64 + 1);
+                    }
+                    // memory.copy and table.copy: the second reserved byte becomes a `nop`
+                    (Some(0xfc), Some(0x0a)) | (Some(0xfc), Some(0x0e)) => {
+                        original.get(start + 3)?;
+                        bytes[start + 3] = 0x01;
+                        rdr.set_position(start as u64 + 4);
+                        idx += 1;
+                        fixups.push((body, idx, Fixup::Nop));
+                    }
+                    // The trunc_sat conversions are 0xfc 0x00 to 0xfc 0x07
+                    (Some(0xfc), Some(&op)) if op <= 0x07 => {
+                        // i32.trunc_f32_s, i32.trunc_f32_u, i32.trunc_f64_s, i32.trunc_f64_u,
+                        // i64.trunc_f32_s, i64.trunc_f32_u, i64.trunc_f64_s, i64.trunc_f64_u
+                        const TRUNC: [u8; 8] = [0xa8, 0xa9, 0xaa, 0xab, 0xae, 0xaf, 0xb0, 0xb1];
+                        bytes[start] = TRUNC[op as usize];
+                        bytes[start + 1] = 0x01;
+                        rdr.set_position(start as u64 + 2);
+                        fixups.push((body, idx, Fixup::TruncSat));
+                        idx += 1;
+                        fixups.push((body, idx, Fixup::Nop));
+                    }
+                    _ => {
+                        Instruction::deserialize(&mut rdr).ok()?;
+                    }
+                }
+                idx += 1;
+            }
+        }
+        rdr.set_position(end);
+    }
+    Some(())
+}
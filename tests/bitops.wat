@@ -0,0 +1,49 @@
+;; 46464032 30232139 20133131 10232239 46434131 30332229
+;; Tests the bit counting, remainder and rotate instructions for i32 and i64
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $main
+    (local $x i64)
+    (local $r i32)
+    (local.set $x (i64.extend_i32_u (global.get $id)))
+    (; r = clz(id) + 100 * popcnt(id) ;)
+    (local.set $r
+      (i32.add
+        (i32.clz (global.get $id))
+        (i32.mul (i32.popcnt (global.get $id)) (i32.const 100))))
+    (; The 64-bit versions need to cross the halves: ctz(id << 32) is 32 + ctz(id), or 64 for 0 ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul
+          (i32.wrap_i64 (i64.ctz (i64.shl (local.get $x) (i64.const 32))))
+          (i32.const 1000))))
+    (; Rotating left by 62 is rotating right by 2, which moves the low bits to the top ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul
+          (i32.wrap_i64
+            (i64.add
+              (i64.clz (i64.rotl (local.get $x) (i64.const 62)))
+              (i64.popcnt (i64.rotr (local.get $x) (i64.const 7)))))
+          (i32.const 100000))))
+    (; rem_s takes the sign of the dividend ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul
+          (i32.add (i32.rem_s (i32.sub (i32.const 0) (global.get $id)) (i32.const 4)) (i32.const 4))
+          (i32.const 10000000))))
+    (; rotr by 33 is the same as rotr by 1 ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.rem_u (i32.rotr (global.get $id) (i32.const 33)) (i32.const 10))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
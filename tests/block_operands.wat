@@ -0,0 +1,41 @@
+;; 12281000 12280103 12281006 12280109 12281012 12280115
+;; Tests blocks with branches as operands, which need everything before them to be evaluated first
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (global $g (mut i32) (i32.const 0))
+
+  (; Appends the digit d to g, so g records the order of calls ;)
+  (func $digit (param $d i32) (result i32)
+    (global.set $g (i32.add (i32.mul (global.get $g) (i32.const 10)) (local.get $d)))
+    (local.get $d))
+
+  (func $main
+    (local $x i32)
+    (local $r i32)
+    (local.set $x (global.get $id))
+    (; r = x + (x * 2 + 100) for odd ids, or x + (x * 2 + 1000) for even ids, using x from before the block ;)
+    (local.set $r
+      (i32.add
+        (local.get $x)
+        (block (result i32)
+          (local.set $x (i32.mul (local.get $x) (i32.const 2)))
+          (drop (br_if 0 (i32.add (local.get $x) (i32.const 100)) (i32.and (global.get $id) (i32.const 1))))
+          (i32.add (local.get $x) (i32.const 1000)))))
+    (; The calls should happen in the order 1, 2, 3 ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul
+          (i32.sub
+            (call $digit (i32.const 1))
+            (block (result i32)
+              (drop (call $digit (i32.const 2)))
+              (br_if 0 (call $digit (i32.const 3)) (i32.const 1))))
+          (i32.const 10000))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add (local.get $r) (i32.mul (global.get $g) (i32.const 100000)))))
+)
@@ -0,0 +1,55 @@
+;; 30210 30220 30210 30230 30240 30240
+;; Tests br_table, drop and unreachable
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (global $calls (mut i32) (i32.const 0))
+
+  (; Counts how many times it's called ;)
+  (func $count (result i32)
+    (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+    (global.get $calls))
+
+  (; Like a Rust `match`: 0 | 2 => 10, 1 => 20, 3 => 30, _ => 40 ;)
+  (func $classify (param $x i32) (result i32)
+    (local $r i32)
+    (block $end
+      (block $default
+        (block $three
+          (block $one
+            (block $zero_two
+              (br_table $zero_two $one $zero_two $three $default (local.get $x)))
+            (local.set $r (i32.const 10))
+            (br $end))
+          (local.set $r (i32.const 20))
+          (br $end))
+        (local.set $r (i32.const 30))
+        (br $end))
+      (local.set $r (i32.const 40)))
+    (local.get $r))
+
+  (func $main
+    (local $r i32)
+    (local $n i32)
+    (local.set $r (call $classify (global.get $id)))
+    (; Dropping the result should still run the call ;)
+    (drop (call $count))
+    (drop (call $count))
+    (local.set $r (i32.add (local.get $r) (i32.mul (global.get $calls) (i32.const 100))))
+    (; A br_table inside a loop, which subtracts 1000 until it's below 1000 and counts the iterations ;)
+    (local.set $r (i32.add (local.get $r) (i32.const 3000)))
+    (loop $l
+      (block $done
+        (local.set $n (i32.add (local.get $n) (i32.const 1)))
+        (local.set $r (i32.sub (local.get $r) (i32.const 1000)))
+        (br_table $done $l (i32.ge_u (local.get $r) (i32.const 1000)))))
+    (local.set $r (i32.add (local.get $r) (i32.mul (local.get $n) (i32.const 10000))))
+    (; Never run ;)
+    (if (i32.gt_u (global.get $id) (i32.const 1000000))
+      (then unreachable))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
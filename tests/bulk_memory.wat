@@ -0,0 +1,28 @@
+;; 33620242 50463268 67306294 84149320 33620242 50463268
+;; Tests memory.fill, memory.copy (including overlapping ranges) and memory.init from passive segments
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+  (memory 1)
+  (data $d "\01\02\03\04\05\06")
+  (data $e "\10\20\30\40")
+
+  (func $main
+    (local $id i32)
+    (local.set $id (i32.and (global.get $id) (i32.const 3)))
+    (; [16, 24) = id+1 ;)
+    (memory.fill (i32.const 16) (i32.add (local.get $id) (i32.const 1)) (i32.const 8))
+    (; Copy 3 of the segment's bytes, starting at id, to 17 ;)
+    (memory.init $d (i32.const 17) (local.get $id) (i32.const 3))
+    (; Overlapping copy up by one byte ;)
+    (memory.copy (i32.const 18) (i32.const 17) (i32.const 3))
+    (data.drop $d)
+    (; [24] = the id'th byte of the second segment ;)
+    (memory.init $e (i32.const 24) (local.get $id) (i32.const 1))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add
+        (i32.load (i32.const 16))
+        (i32.add (i32.load8_u (i32.const 21)) (i32.load8_u (i32.const 24))))))
+)
@@ -0,0 +1,47 @@
+;; 1016836 1017935 1019034 1020133 1021232 1022331
+;; Tests narrow loads and stores, which only touch part of a word in memory
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+  (memory 1)
+
+  (func $main
+    (local $r i32)
+    (i32.store (i32.const 1000) (i32.const 0x11223344))
+    (i32.store8 (i32.const 1001) (i32.add (global.get $id) (i32.const 0xf0)))
+    (i32.store16 (i32.const 1002) (i32.add (global.get $id) (i32.const 0x8000)))
+    (i64.store32 (i32.const 1004) (i64.sub (i64.const -1) (i64.extend_i32_u (global.get $id))))
+    (i64.store8 (i32.const 1007) (i64.extend_i32_u (global.get $id)))
+    (; The first byte shouldn't have changed ;)
+    (local.set $r (i32.load8_u (i32.const 1000)))
+    (; Add 1000 * the sign-extended second byte, which is negative ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul (i32.load8_s (i32.const 1001)) (i32.const 1000))))
+    (; Subtract the sign-extended top half ;)
+    (local.set $r
+      (i32.sub (local.get $r) (i32.load16_s (i32.const 1002))))
+    (; Add the byte we wrote into the second word times 100, plus 1 if the rest is intact ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.wrap_i64
+          (i64.add
+            (i64.mul (i64.load8_u (i32.const 1007)) (i64.const 100))
+            (i64.extend_i32_u
+              (i64.eq
+                (i64.load16_s (i32.const 1004))
+                (i64.load32_s (i32.const 1004))))))))
+    (; Add 1000000 if the whole word reads back the same as the two halves ;)
+    (if (i32.eq
+          (i32.load (i32.const 1000))
+          (i32.or
+            (i32.load16_u (i32.const 1000))
+            (i32.shl (i32.load16_u (i32.const 1002)) (i32.const 16))))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 1000000)))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
@@ -29,7 +29,8 @@
     (local.get $a))
 
   ;; TODO support multiple return values
-  ;; Of course, parity_wasm currently doesn't even support that, so it may be a ways off
+  ;; parity_wasm fails to parse a function type with more than one result, so that needs to change first.
+  ;; After that, `Fun::ty` and block types need to hold a list of types, and we'd return a struct from the SPIR-V function.
 
   (func $main
     (local $num i32)
@@ -0,0 +1,27 @@
+;; 1020 1019 1040 1023 1020 1019
+;; Tests calling functions through a table, with arguments and return values
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (type $binop (func (param i32 i32) (result i32)))
+  (type $unop (func (param i32) (result i32)))
+
+  (table 6 funcref)
+  (elem (i32.const 0) $add $sub $mul $add $neg)
+
+  (func $add (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1)))
+  (func $sub (param i32 i32) (result i32) (i32.sub (local.get 0) (local.get 1)))
+  (func $mul (param i32 i32) (result i32) (i32.mul (local.get 0) (local.get 1)))
+  (func $neg (param i32) (result i32) (i32.sub (i32.const 0) (local.get 0)))
+
+  (func $main
+    (local $id i32)
+    (local.set $id (i32.and (global.get $id) (i32.const 3)))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add
+        (call_indirect (type $binop) (i32.const 20) (local.get $id) (local.get $id))
+        (call_indirect (type $unop) (i32.const -1000) (i32.const 4)))))
+)
@@ -0,0 +1,27 @@
+;; 101000 100000 101004 100001 101008 100002
+;; Tests calls whose results are used where their type matters, like `select` and conversions
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $double (param $x i64) (result i64)
+    (i64.mul (local.get $x) (i64.const 2)))
+
+  (func $half (param $x f32) (result f32)
+    (f32.div (local.get $x) (f32.const 2)))
+
+  (func $odd (param $x i32) (result i32)
+    (i32.and (local.get $x) (i32.const 1)))
+
+  (func $main
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add
+        (; 1000 + id * 2 for even ids, otherwise id / 2 ;)
+        (select
+          (i32.wrap_i64 (call $double (i64.extend_i32_u (i32.add (global.get $id) (i32.const 500)))))
+          (i32.trunc_f32_u (call $half (f32.convert_i32_u (global.get $id))))
+          (i32.eqz (call $odd (global.get $id))))
+        (; Calls as arguments to calls ;)
+        (i32.wrap_i64 (call $double (call $double (i64.const 25000))))))))
@@ -0,0 +1,18 @@
+;; 50462976 2304 84148224 526086 50462976 2304
+;; Tests multiple data segments with odd sizes and offsets, and the zeros in the gaps between them
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+  (memory 1)
+  (data (i32.const 1001) "\01\02\03")
+  (data (i32.const 1010) "\04\05\06\07\08")
+  (data (i32.const 1005) "\09")
+
+  (func $main
+    (local $p i32)
+    (local.set $p (i32.add (i32.const 1000) (i32.mul (i32.and (global.get $id) (i32.const 3)) (i32.const 4))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.load align=1 (local.get $p))))
+)
@@ -0,0 +1,59 @@
+;; 304031000 305042002 306052004 307062006
+;; Tests values left on the stack before unconditional branches, which are thrown away but still need to run
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (global $count (mut i32) (i32.const 0))
+
+  (; Counts how many times it's called, so we can tell it ran even though its result is thrown away ;)
+  (func $tick (result i32)
+    (global.set $count (i32.add (global.get $count) (i32.const 1)))
+    (global.get $count))
+
+  (; The code after the branch is never run, and uses the stack in ways that only work there ;)
+  (func $br (param $x i32) (result i32)
+    (block $b (result i32)
+      (call $tick)
+      (i32.mul (local.get $x) (i32.const 2))
+      (br $b)
+      (i32.add)
+      (if (then (nop)) (else (nop)))
+      (block (result i64) (i64.const 1))))
+
+  (func $br_table (param $x i32) (result i32)
+    (block $b
+      (block $a
+        (call $tick)
+        (br_table $a $b (local.get $x)))
+      (return (i32.const 10)))
+    (i32.const 20))
+
+  (func $ret (param $x i32) (result i32)
+    (call $tick)
+    (local.get $x)
+    (return (i32.add (local.get $x) (i32.const 3))))
+
+  (; The `then` branch ends without a value, since it never ends ;)
+  (func $trap (param $x i32) (result i32)
+    (if (result i32) (i32.gt_u (local.get $x) (i32.const 100))
+      (then (i32.const 5) (unreachable))
+      (else (i32.add (local.get $x) (i32.const 4)))))
+
+  (func $main
+    (local $id i32)
+    (local $v i32)
+    (local.set $id (i32.and (global.get $id) (i32.const 7)))
+    (local.set $v
+      (i32.add
+        (i32.add
+          (call $br (local.get $id))
+          (i32.mul (call $br_table (local.get $id)) (i32.const 100)))
+        (i32.add
+          (i32.mul (call $ret (local.get $id)) (i32.const 10000))
+          (i32.mul (call $trap (local.get $id)) (i32.const 1000000)))))
+    (call $store
+      (i32.mul (local.get $id) (i32.const 4))
+      (i32.add (local.get $v) (i32.mul (global.get $count) (i32.const 100000000)))))
+)
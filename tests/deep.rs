@@ -0,0 +1,62 @@
+//! These check that big functions compile without overflowing the stack.
+//! They don't need a GPU, since they never get as far as running anything.
+use wasm_vk::*;
+
+/// Compiles a module written in WAT
+fn compile(wat: &str) -> Result<spirv::Module, CompileError> {
+    let binary = wabt::wat2wasm(wat).unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    spirv::Ctx::new().module(&w)
+}
+
+/// A module that adds 1 to the thread id `len` times in one long chain of instructions, inside of `depth` nested blocks.
+/// It's written flat instead of folded, so it's just as deep to us but the WAT parser doesn't need to recurse.
+fn deep_module(len: usize, depth: usize) -> String {
+    format!(
+        r#"(module
+            (import "spv" "id" (global $id i32))
+            (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+            (func $main
+              {}
+              global.get $id
+              i32.const 4
+              i32.mul
+              global.get $id
+              {}
+              call $store
+              {})
+            (start $main))"#,
+        "block ".repeat(depth),
+        "i32.const 1 i32.add ".repeat(len),
+        "end ".repeat(depth),
+    )
+}
+
+#[test]
+fn long_chain() {
+    if let Err(e) = compile(&deep_module(20000, 0)) {
+        panic!("Expected it to compile, got {:?}", e);
+    }
+}
+
+#[test]
+fn deep_blocks() {
+    if let Err(e) = compile(&deep_module(1, 2000)) {
+        panic!("Expected it to compile, got {:?}", e);
+    }
+}
+
+#[test]
+fn long_chain_in_deep_blocks() {
+    if let Err(e) = compile(&deep_module(20000, 2000)) {
+        panic!("Expected it to compile, got {:?}", e);
+    }
+}
+
+#[test]
+fn check_deep_blocks() {
+    let binary = wabt::wat2wasm(&deep_module(1, 2000)).unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    let problems = check::module(&w);
+    assert!(problems.is_empty(), "{:?}", problems);
+}
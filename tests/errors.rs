@@ -0,0 +1,381 @@
+//! These check that modules we can't compile give the right `CompileError` instead of panicking.
+//! They don't need a GPU, since they never get as far as running anything.
+use wasm_vk::*;
+
+/// Compiles a module written in WAT
+fn compile(wat: &str) -> Result<spirv::Module, CompileError> {
+    let mut features = wabt::Features::new();
+    features.enable_bulk_memory();
+    let binary = wabt::wat2wasm_with_features(wat, features).unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    spirv::Ctx::new().module(&w)
+}
+
+/// Compiles a module written in WAT without validating it first, so we can test invalid modules
+fn compile_unchecked(wat: &str) -> Result<spirv::Module, CompileError> {
+    let binary = wabt::Wat2Wasm::new().validate(false).convert(wat).unwrap();
+    let w = wasm::deserialize_buffer(binary.as_ref()).unwrap();
+    spirv::Ctx::new().module(&w)
+}
+
+#[test]
+fn unknown_import() {
+    let e = compile(
+        r#"(module
+            (import "env" "print" (func $print (param i32)))
+            (func $main)
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Import { name, .. }) => assert_eq!(name, "env.print"),
+        x => panic!("Expected an import error, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn buffer_import_type() {
+    let e = compile(
+        r#"(module
+            (import "spv" "buffer:0:0:load" (func $load (param i32)))
+            (func $main)
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Import { name, .. }) => assert_eq!(name, "spv.buffer:0:0:load"),
+        x => panic!("Expected an import error, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn unsupported_instruction() {
+    let e = compile(
+        r#"(module
+            (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+            (table 1 funcref)
+            (func $main
+              (table.copy (i32.const 0) (i32.const 0) (i32.const 0)))
+            (start $main))"#,
+    );
+    match e {
+        // Imported functions come first, and the three constants are before it
+        Err(CompileError::Unsupported { fun, offset, .. }) => assert_eq!((fun, offset), (1, 3)),
+        x => panic!("Expected an unsupported instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn data_too_big() {
+    let e = compile(
+        r#"(module
+            (memory 1)
+            (data (i32.const 0) "wasm-vk")
+            (data (i32.const 200) "only has 128 bytes of memory")
+            (func $main)
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Data { segment, .. }) => assert_eq!(segment, 1),
+        x => panic!("Expected a data segment error, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn imported_start() {
+    let e = compile(
+        r#"(module
+            (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+            (start $store))"#,
+    );
+    match e {
+        Err(CompileError::Start { fun }) => assert_eq!(fun, 0),
+        x => panic!("Expected a start function error, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn check_finds_everything() {
+    let mut features = wabt::Features::new();
+    features.enable_bulk_memory();
+    let binary = wabt::wat2wasm_with_features(
+        r#"(module
+            (import "env" "print" (func $print (param i32)))
+            (table 1 funcref)
+            (func $first
+              (table.copy (i32.const 0) (i32.const 0) (i32.const 0)))
+            (func $second
+              (table.copy (i32.const 0) (i32.const 0) (i32.const 0))
+              (table.copy (i32.const 0) (i32.const 0) (i32.const 0)))
+            (start $first))"#,
+        features,
+    )
+    .unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    let problems = check::module(&w);
+    // The import, and then each `table.copy`
+    assert_eq!(problems.len(), 4, "{:?}", problems);
+    assert!(matches!(problems[0].error, CompileError::Import { .. }));
+    assert_eq!(problems[1].error.fun(), Some(1));
+    assert_eq!(problems[2].error.fun(), Some(2));
+    assert_eq!(problems[3].error.fun(), Some(2));
+}
+
+#[test]
+fn check_names_functions() {
+    // Like `compile_unchecked`, but with a name section
+    let binary = wabt::Wat2Wasm::new()
+        .validate(false)
+        .write_debug_names(true)
+        .convert(
+            r#"(module
+            (import "env" "print" (func $print (param i32)))
+            (func $fine)
+            (func $broken
+              (drop (local.get 0)))
+            (start $fine))"#,
+        )
+        .unwrap();
+    let w = wasm::deserialize_buffer(binary.as_ref()).unwrap();
+    let problems = check::module(&w);
+    // The import isn't in a function, and the names count imported functions too
+    assert_eq!(problems.len(), 2, "{:?}", problems);
+    assert_eq!(problems[0].fun_name, None);
+    assert_eq!(problems[1].fun_name.as_deref(), Some("broken"));
+    assert!(problems[1].to_string().starts_with("$broken: "));
+}
+
+#[test]
+fn atomics_unsupported() {
+    let mut features = wabt::Features::new();
+    features.enable_threads();
+    let binary = wabt::wat2wasm_with_features(
+        r#"(module
+            (memory 1 1 shared)
+            (func $main
+              (drop (i32.atomic.rmw.add (i32.const 0) (i32.const 1))))
+            (start $main))"#,
+        features,
+    )
+    .unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    match spirv::Ctx::new().module(&w) {
+        Err(CompileError::Unsupported { fun, offset, .. }) => assert_eq!((fun, offset), (0, 2)),
+        x => panic!("Expected an unsupported instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn local_out_of_range() {
+    let e = compile_unchecked(
+        r#"(module
+            (func $main (param i32)
+              (drop (local.get 1)))
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Invalid { fun, offset, .. }) => assert_eq!((fun, offset), (0, 0)),
+        x => panic!("Expected an invalid instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn global_out_of_range() {
+    let e = compile_unchecked(
+        r#"(module
+            (global i32 (i32.const 0))
+            (func $main
+              (global.set 1 (i32.const 2)))
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Invalid { fun, offset, .. }) => assert_eq!((fun, offset), (0, 1)),
+        x => panic!("Expected an invalid instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn function_out_of_range() {
+    let e = compile_unchecked(
+        r#"(module
+            (func $main
+              (call 3))
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Invalid { fun, offset, .. }) => assert_eq!((fun, offset), (0, 0)),
+        x => panic!("Expected an invalid instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn set_thread_id() {
+    let e = compile_unchecked(
+        r#"(module
+            (import "spv" "id" (global $id i32))
+            (func $main
+              (global.set $id (i32.const 1)))
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Invalid { fun, offset, .. }) => assert_eq!((fun, offset), (0, 1)),
+        x => panic!("Expected an invalid instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn imported_global() {
+    let wat = r#"(module
+        (import "env" "offset" (global $offset i32))
+        (memory 1)
+        (data (global.get $offset) "wasm-vk")
+        (func $main)
+        (start $main))"#;
+    match compile(wat) {
+        Err(CompileError::Import { name, .. }) => assert_eq!(name, "env.offset"),
+        x => panic!("Expected an import error, got {:?}", x.map(|_| ())),
+    }
+
+    let mut features = wabt::Features::new();
+    features.enable_bulk_memory();
+    let binary = wabt::wat2wasm_with_features(wat, features).unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    assert!(spirv::Ctx::new()
+        .with_global("env.offset", ir::Const::I32(64))
+        .module(&w)
+        .is_ok());
+
+    // Checking agrees with compiling, with and without the global
+    assert!(matches!(check::module(&w)[0].error, CompileError::Import { .. }));
+    let ctx = spirv::Ctx::new().with_global("env.offset", ir::Const::I32(64));
+    assert!(check::module_with(&ctx, &w).is_empty());
+}
+
+#[test]
+fn offset_after_memory_copy() {
+    let e = compile(
+        r#"(module
+            (memory 1)
+            (table 1 funcref)
+            (func $main
+              (memory.copy (i32.const 0) (i32.const 4) (i32.const 4))
+              (table.copy (i32.const 0) (i32.const 0) (i32.const 0)))
+            (start $main))"#,
+    );
+    match e {
+        // The `memory.copy` is one instruction, even though we read it as two
+        Err(CompileError::Unsupported { fun, offset, .. }) => assert_eq!((fun, offset), (0, 7)),
+        x => panic!("Expected an unsupported instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn offset_after_trunc_sat() {
+    let mut features = wabt::Features::new();
+    features.enable_bulk_memory();
+    features.enable_sat_float_to_int();
+    let binary = wabt::wat2wasm_with_features(
+        r#"(module
+            (table 1 funcref)
+            (func $main
+              (drop (i32.trunc_sat_f32_s (f32.const 1.5)))
+              (table.copy (i32.const 0) (i32.const 0) (i32.const 0)))
+            (start $main))"#,
+        features,
+    )
+    .unwrap();
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    match spirv::Ctx::new().module(&w) {
+        Err(CompileError::Unsupported { fun, offset, .. }) => assert_eq!((fun, offset), (0, 6)),
+        x => panic!("Expected an unsupported instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn memory_init_out_of_range() {
+    let mut features = wabt::Features::new();
+    features.enable_bulk_memory();
+    let mut binary = wabt::wat2wasm_with_features(
+        r#"(module
+            (memory 1)
+            (data $d "wasm-vk")
+            (func $main
+              (memory.init $d (i32.const 0) (i32.const 0) (i32.const 1)))
+            (start $main))"#,
+        features,
+    )
+    .unwrap();
+    // wabt won't write a `memory.init` of a segment that doesn't exist, so we change the index afterwards
+    let seg = binary
+        .windows(4)
+        .position(|x| x == [0xfc, 0x08, 0x00, 0x00])
+        .unwrap()
+        + 2;
+    binary[seg] = 1;
+    let w = wasm::deserialize_buffer(&binary).unwrap();
+    match spirv::Ctx::new().module(&w) {
+        Err(CompileError::Invalid { fun, offset, .. }) => assert_eq!((fun, offset), (0, 3)),
+        x => panic!("Expected an invalid instruction, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn start_out_of_range() {
+    let e = compile_unchecked(
+        r#"(module
+            (func $main)
+            (start 3))"#,
+    );
+    match e {
+        Err(CompileError::Start { fun }) => assert_eq!(fun, 3),
+        x => panic!("Expected a start function error, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn import_type_out_of_range() {
+    let e = compile_unchecked(
+        r#"(module
+            (type (func (param i32) (result i32)))
+            (import "spv" "buffer:0:0:load" (func (type 3)))
+            (func $main)
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Import { name, .. }) => assert_eq!(name, "spv.buffer:0:0:load"),
+        x => panic!("Expected an import error, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn element_out_of_range() {
+    // A negative offset is a huge unsigned one
+    for offset in &["-1", "1", "0x7fffffff"] {
+        let e = compile(&format!(
+            r#"(module
+                (table 2 funcref)
+                (elem (i32.const {}) $main $main)
+                (func $main)
+                (start $main))"#,
+            offset
+        ));
+        match e {
+            Err(CompileError::Element { segment, .. }) => assert_eq!(segment, 0),
+            x => panic!("Expected an element segment error, got {:?}", x.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn data_past_address_space() {
+    let e = compile(
+        r#"(module
+            (memory 1)
+            (data (i32.const 0) "wasm-vk")
+            (data (i32.const -4) "abcdefgh")
+            (func $main)
+            (start $main))"#,
+    );
+    match e {
+        Err(CompileError::Data { segment, .. }) => assert_eq!(segment, 1),
+        x => panic!("Expected a data segment error, got {:?}", x.map(|_| ())),
+    }
+}
@@ -0,0 +1,37 @@
+;; 100 100 101 102 102 102
+;; Tests 64-bit float arithmetic, rounding, conversions and globals
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (global $quarter f64 (f64.const 0.25))
+
+  (; Returns 2x, going through sqrt so we know it's using f64 precision ;)
+  (func $double (param $x f64) (result f64)
+    (f64.sqrt (f64.mul (f64.mul (local.get $x) (local.get $x)) (f64.const 4))))
+
+  (func $main
+    (local $x f64)
+    (local $r i32)
+    (local.set $x (f64.convert_i32_u (global.get $id)))
+    (; r = 2 * id ;)
+    (local.set $r
+      (i32.trunc_f64_u (f64.add (call $double (local.get $x)) (f64.const 0.5))))
+    (; x + 0.25 is exactly representable as an f32, so this adds 100 ;)
+    (if (f64.eq
+          (f64.promote_f32 (f32.demote_f64 (f64.add (local.get $x) (global.get $quarter))))
+          (f64.add (local.get $x) (global.get $quarter)))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 100)))))
+    (; Subtract -1.5 * id rounded to the nearest even number ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.wrap_i64
+          (i64.trunc_f64_s
+            (f64.nearest
+              (f64.mul (f64.copysign (local.get $x) (f64.const -1)) (f64.const 1.5)))))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
@@ -0,0 +1,54 @@
+;; 1100000 11110127 11121128 11072128 11062129 11052129
+;; Tests f32 rounding and copysign, and reinterpreting between integers and floats
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $main
+    (local $x f32)
+    (local $r i32)
+    (local.set $x (f32.convert_i32_u (global.get $id)))
+    (; r = the biased exponent of id ;)
+    (local.set $r (i32.shr_u (i32.reinterpret_f32 (local.get $x)) (i32.const 23)))
+    (; Add 1000 * id / 2 rounded to the nearest even number ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul
+          (i32.trunc_f32_s (f32.nearest (f32.mul (local.get $x) (f32.const 0.5))))
+          (i32.const 1000))))
+    (; Add 10000 * (10 + id), negated if id > 2.5 ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul
+          (i32.add
+            (i32.trunc_f32_s
+              (f32.trunc
+                (f32.copysign
+                  (f32.add (local.get $x) (f32.const 0.25))
+                  (f32.sub (f32.const 2.5) (local.get $x)))))
+            (i32.const 10))
+          (i32.const 10000))))
+    (; Add 1000000 if putting id in the exponent gives 2^id (id is kept small so this doesn't overflow) ;)
+    (if (i32.eq
+          (i32.trunc_f32_u
+            (f32.reinterpret_i32
+              (i32.add
+                (i32.const 0x3f800000)
+                (i32.shl (i32.and (global.get $id) (i32.const 15)) (i32.const 23)))))
+          (i32.shl (i32.const 1) (i32.and (global.get $id) (i32.const 15))))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 1000000)))))
+    (; Add 10000000 if the f64 exponent matches the f32 one ;)
+    (if (i64.eq
+          (i64.shr_u
+            (i64.reinterpret_f64 (f64.reinterpret_i64 (i64.reinterpret_f64 (f64.promote_f32 (local.get $x)))))
+            (i64.const 52))
+          (i64.extend_i32_u
+            (i32.add (i32.shr_u (i32.reinterpret_f32 (local.get $x)) (i32.const 23)) (i32.const 896))))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 10000000)))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
@@ -0,0 +1,22 @@
+;; 2500 3000 3500 4000 4500 5000
+;; Tests globals of every type, including float initializers and initializing a global with `global.get`
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (global $scale (mut f32) (f32.const 2.5))
+  (global $offset f64 (f64.const 0.25))
+  (global $big i64 (i64.const 1000))
+  (; A copy of the thread id ;)
+  (global $me i32 (global.get $id))
+
+  (func $main
+    (global.set $scale (f32.mul (global.get $scale) (f32.const 100)))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add
+        (i32.trunc_f32_u (f32.mul (global.get $scale) (f32.convert_i32_u (i32.add (global.get $me) (i32.const 10)))))
+        (i32.trunc_f64_u (f64.mul (global.get $offset) (f64.convert_i64_u
+          (i64.mul (global.get $big) (i64.extend_i32_u (global.get $me)))))))))
+)
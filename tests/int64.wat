@@ -0,0 +1,40 @@
+;; 7 1117 1127 1137 1147 1157
+;; Tests 64-bit integer arithmetic, comparisons, conversions, params and globals
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (global $big (mut i64) (i64.const 4294967296))
+
+  (; Puts x in the top half and 7 in the bottom half ;)
+  (func $pack (param $x i64) (result i64)
+    (i64.add
+      (i64.mul (local.get $x) (global.get $big))
+      (i64.const 7)))
+
+  (func $main
+    (local $a i64)
+    (local $r i64)
+    (local.set $a (call $pack (i64.extend_i32_u (global.get $id))))
+    (; r = id * 10 + 7 ;)
+    (local.set $r
+      (i64.add
+        (i64.mul (i64.shr_u (local.get $a) (i64.const 32)) (i64.const 10))
+        (i64.and (local.get $a) (i64.const 0xffffffff))))
+    (; Add 1000 if anything is in the top half ;)
+    (if (i64.gt_u (local.get $a) (i64.const 0xffffffff))
+      (then (local.set $r (i64.add (local.get $r) (i64.const 1000)))))
+    (; Add 100 if -id is negative, which checks that extend_i32_s extends the sign bit ;)
+    (local.set $r
+      (i64.add
+        (local.get $r)
+        (i64.and
+          (i64.shr_s
+            (i64.extend_i32_s (i32.sub (i32.const 0) (global.get $id)))
+            (i64.const 63))
+          (i64.const 100))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.wrap_i64 (local.get $r))))
+)
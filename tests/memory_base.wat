@@ -0,0 +1,19 @@
+;; 1000 1002 1004 1006 1000 1002
+;; Tests data segments and globals placed relative to an imported `__memory_base`, like position-independent code does
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+  (import "env" "__memory_base" (global $base i32))
+  (memory 1)
+  (global $start i32 (global.get $base))
+  (; Four u16s: 1000, 1002, 1004, 1006 ;)
+  (data (global.get $base) "\e8\03\ea\03\ec\03\ee\03")
+
+  (func $main
+    (local $id i32)
+    (local.set $id (i32.and (global.get $id) (i32.const 3)))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.load16_u (i32.add (global.get $start) (i32.mul (local.get $id) (i32.const 2))))))
+)
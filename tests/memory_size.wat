@@ -0,0 +1,20 @@
+;; 1 1 1 1 1 1
+;; Tests memory.size and memory.grow, which can only succeed when growing by 0 pages
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+  (memory 1)
+
+  (func $main
+    (local $r i32)
+    (local.set $r (i32.eq (memory.size) (i32.const 1)))
+    (; Growing by 0 returns the old size ;)
+    (local.set $r (i32.and (local.get $r) (i32.eq (memory.grow (i32.const 0)) (i32.const 1))))
+    (; We can't grow any further ;)
+    (local.set $r (i32.and (local.get $r) (i32.eq (memory.grow (i32.add (global.get $id) (i32.const 1))) (i32.const -1))))
+    (local.set $r (i32.and (local.get $r) (i32.eq (memory.size) (i32.const 1))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
@@ -0,0 +1,36 @@
+;; 1432 13142 118452 1066162 9595472 86359182
+;; Tests branches out of several nested blocks and loops at once, including to the start of an outer loop
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $main
+    (local $i i32)
+    (local $j i32)
+    (local $r i32)
+    (block $done
+      (loop $outer
+        (if (i32.ge_u (local.get $i) (i32.add (global.get $id) (i32.const 3)))
+          (then (br $done)))
+        (local.set $j (i32.const 0))
+        (block $next
+          (loop $inner
+            (br_if $next (i32.ge_u (local.get $j) (local.get $i)))
+            (br_if $done (i32.gt_u (i32.mul (local.get $i) (local.get $j)) (i32.add (global.get $id) (i32.const 6))))
+            (local.set $r (i32.add (i32.mul (local.get $r) (i32.const 3)) (i32.add (local.get $j) (i32.const 1))))
+            (local.set $j (i32.add (local.get $j) (i32.const 1)))
+            (; Go straight to the next i once j gets to 2 ;)
+            (if (i32.eq (local.get $j) (i32.const 2))
+              (then
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $outer)))
+            (br $inner)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $outer)))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add
+        (i32.mul (local.get $r) (i32.const 100))
+        (i32.add (i32.mul (local.get $i) (i32.const 10)) (local.get $j)))))
+)
@@ -38,6 +38,27 @@ test!(fib);
 test!(rust);
 test!(call);
 test!(typed_if);
+test!(int64);
+test!(float64);
+test!(bitops);
+test!(floatbits);
+test!(sign_ext);
+test!(bytes);
+test!(unaligned);
+test!(br_table);
+test!(typed_blocks);
+test!(call_indirect);
+test!(memory_size);
+test!(bulk_memory);
+test!(globals);
+test!(data_segments);
+test!(select);
+test!(call_types);
+test!(block_operands);
+test!(nested_loops);
+test!(dead_values);
+test!(memory_base);
+test!(trunc_sat);
 
 // --------------------
 // MORE FRAMEWORK STUFF
@@ -66,7 +87,12 @@ fn run_test(test: &'static str) -> std::io::Result<()> {
 
     let mut buf = Vec::new();
     buf_reader.read_to_end(&mut buf)?;
-    match wabt::wat2wasm(buf) {
+    // Enable the proposals we support so the tests can use them
+    let mut features = wabt::Features::new();
+    features.enable_sign_extension();
+    features.enable_bulk_memory();
+    features.enable_sat_float_to_int();
+    match wabt::wat2wasm_with_features(buf, features) {
         Ok(binary) => {
             let w = wasm::deserialize_buffer(&binary).unwrap();
             let got = run_module(w);
@@ -96,7 +122,7 @@ fn run_test(test: &'static str) -> std::io::Result<()> {
 fn run_module(w: wasm::Module) -> Vec<u32> {
     // First, we generate SPIR-V
     let ctx = spirv::Ctx::new();
-    let m = ctx.module(&w);
+    let m = ctx.module(&w).unwrap();
     println!("{}", {
         use rspirv::binary::Disassemble;
         m.disassemble()
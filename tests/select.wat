@@ -0,0 +1,23 @@
+;; 300220 303210 303120 303110 300220 303210
+;; Tests select with different types, and that both operands are always evaluated
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $main
+    (local $id i32)
+    (local $r i32)
+    (local $a i32)
+    (local $b i32)
+    (local.set $id (i32.and (global.get $id) (i32.const 3)))
+    (local.set $r
+      (select (local.tee $a (i32.const 10)) (local.tee $b (i32.const 20)) (i32.and (local.get $id) (i32.const 1))))
+    (local.set $r (i32.add (local.get $r)
+      (i32.trunc_f32_s (select (f32.const 100.5) (f32.const 200.5) (i32.ge_u (local.get $id) (i32.const 2))))))
+    (local.set $r (i32.add (local.get $r)
+      (i32.wrap_i64 (select (i64.const 3000) (i64.extend_i32_u (local.get $id)) (local.get $id)))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add (local.get $r) (i32.mul (i32.add (local.get $a) (local.get $b)) (i32.const 10000)))))
+)
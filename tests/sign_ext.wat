@@ -0,0 +1,38 @@
+;; 1101126 11101127 11110872 11110873 11100874 11100875
+;; Tests the sign extension instructions
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $main
+    (local $x i32)
+    (local $r i32)
+    (; x = id * 0x4100 + 0x7e, so the low byte goes negative for id >= 1 and the low half for id >= 2 ;)
+    (local.set $x
+      (i32.add
+        (i32.mul (global.get $id) (i32.const 0x4100))
+        (i32.add (i32.const 0x7e) (global.get $id))))
+    (; r = extend8(x) + 1000 ;)
+    (local.set $r (i32.add (i32.extend8_s (local.get $x)) (i32.const 1000)))
+    (; Add 10000 if the low half is negative ;)
+    (if (i32.lt_s (i32.extend16_s (local.get $x)) (i32.const 0))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 10000)))))
+    (; The i64 versions should agree with the i32 ones ;)
+    (if (i64.eq
+          (i64.extend8_s (i64.extend_i32_u (local.get $x)))
+          (i64.extend_i32_s (i32.extend8_s (local.get $x))))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 100000)))))
+    (if (i64.eq
+          (i64.extend16_s (i64.extend_i32_u (local.get $x)))
+          (i64.extend_i32_s (i32.extend16_s (local.get $x))))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 1000000)))))
+    (; extend32_s of a value with the top bit set fills the top half ;)
+    (if (i64.lt_s
+          (i64.extend32_s (i64.extend_i32_u (i32.sub (i32.const 0) (global.get $id))))
+          (i64.const 0))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 10000000)))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)
@@ -0,0 +1,42 @@
+;; 2147483648 2147483648 2794967296 0 1500000000 2147483647 2147483647 0 0 0 0 0 1500000000 3000000000 4294967295 0 2147483648 2199491503 3247229399 0 1047737896 2095475792 2147483647 0 0 0 0 0 1047737920 2095475840 3143213568 0
+;; Tests the saturating float to int conversions
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (func $main
+    (local $x f64)
+    (local $y f32)
+    (; Threads 0-7 each write to four slots, 8 apart, and the others don't write anything ;)
+    (if (i32.ge_u (global.get $id) (i32.const 8))
+      (then (return)))
+    (; x = (id - 3) * 1.5e9, which is out of range for i32 at both ends, or NaN for id 7 ;)
+    (local.set $x
+      (select
+        (f64.const nan)
+        (f64.mul
+          (f64.convert_i32_s (i32.sub (global.get $id) (i32.const 3)))
+          (f64.const 1.5e9))
+        (i32.eq (global.get $id) (i32.const 7))))
+    (local.set $y (f32.demote_f64 (local.get $x)))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.trunc_sat_f64_s (local.get $x)))
+    (call $store
+      (i32.mul (i32.add (global.get $id) (i32.const 8)) (i32.const 4))
+      (i32.trunc_sat_f32_u (local.get $y)))
+    (; The i64 versions get values 3e9 times bigger, and we store the top half ;)
+    (call $store
+      (i32.mul (i32.add (global.get $id) (i32.const 16)) (i32.const 4))
+      (i32.wrap_i64
+        (i64.shr_u
+          (i64.trunc_sat_f64_s (f64.mul (local.get $x) (f64.const 3e9)))
+          (i64.const 32))))
+    (call $store
+      (i32.mul (i32.add (global.get $id) (i32.const 24)) (i32.const 4))
+      (i32.wrap_i64
+        (i64.shr_u
+          (i64.trunc_sat_f32_u (f32.mul (local.get $y) (f32.const 3e9)))
+          (i64.const 32)))))
+)
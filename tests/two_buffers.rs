@@ -61,7 +61,7 @@ fn two_buffers() -> std::io::Result<()> {
 fn run_module(w: wasm::Module) -> Vec<u32> {
     // First, we generate SPIR-V
     let ctx = spirv::Ctx::new();
-    let m = ctx.module(&w);
+    let m = ctx.module(&w).unwrap();
     println!("{}", {
         use rspirv::binary::Disassemble;
         m.disassemble()
@@ -0,0 +1,47 @@
+;; 300000003 201001103 2003004 3006107 4010009 5015107
+;; Tests blocks and loops with results, and branches that carry values out of them
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+
+  (; Returns early with a value for even numbers ;)
+  (func $half (param $x i32) (result i32)
+    (if (i32.eqz (i32.and (local.get $x) (i32.const 1)))
+      (then (return (i32.shr_u (local.get $x) (i32.const 1)))))
+    (i32.const 100))
+
+  (; Sums 1..=n with a loop that produces the sum ;)
+  (func $sum (param $n i32) (result i32)
+    (local $acc i32)
+    (loop $l (result i32)
+      (local.set $acc (i32.add (local.get $acc) (local.get $n)))
+      (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+      (br_if $l (i32.gt_s (local.get $n) (i32.const 0)))
+      (local.get $acc)))
+
+  (; Picks a value with br_table, with the value carried by the branch ;)
+  (func $pick (param $x i32) (result i32)
+    (block $d (result i32)
+      (block $b (result i32)
+        (block $a (result i32)
+          (br_table $a $b $d (i32.mul (local.get $x) (i32.const 10)) (local.get $x)))
+        (i32.add (i32.const 1000)))
+      (i32.add (i32.const 2000))))
+
+  (func $main
+    (local $id i32)
+    (local.set $id (i32.and (global.get $id) (i32.const 7)))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (i32.add
+        (i32.add
+          (call $half (local.get $id))
+          (i32.mul (call $sum (local.get $id)) (i32.const 1000)))
+        (i32.add
+          (i32.mul (call $pick (local.get $id)) (i32.const 100000))
+          (; br_if with a value: the value stays on the stack if it doesn't branch ;)
+          (block $b (result i32)
+            (drop (br_if $b (i32.const 7) (i32.gt_u (local.get $id) (i32.const 2))))
+            (i32.const 3))))))
+)
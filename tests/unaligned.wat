@@ -0,0 +1,46 @@
+;; 255101007 255101008 255101009 255101010 255101011 255101012
+;; Tests loads and stores at addresses that aren't 4-byte aligned
+(module
+  (start $main)
+  (import "spv" "id" (global $id i32))
+  (import "spv" "buffer:0:0:store" (func $store (param i32 i32)))
+  (memory 1)
+
+  (func $main
+    (local $p i32)
+    (local $r i32)
+    (; p is at a different byte within the word for each thread ;)
+    (local.set $p (i32.add (i32.const 1000) (i32.and (global.get $id) (i32.const 3))))
+    (i64.store (i32.const 1000) (i64.const 0))
+    (i64.store (i32.const 1008) (i64.const -1))
+    (; With align=0 we can't assume anything ;)
+    (i32.store offset=1 align=1 (local.get $p) (i32.add (i32.const 0x11223300) (global.get $id)))
+    (i32.store16 offset=6 align=1 (local.get $p) (i32.const 0xabcd))
+    (; r = the i32 we stored, minus 0x11223300 ;)
+    (local.set $r
+      (i32.sub (i32.load offset=1 align=1 (local.get $p)) (i32.const 0x11223300)))
+    (; Add 1000 if the 16-bit value came back ;)
+    (if (i32.eq (i32.load16_u offset=6 align=1 (local.get $p)) (i32.const 0xabcd))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 1000)))))
+    (; Add 10000 * the byte before the i32, which should still be 0 ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul (i32.load8_u (local.get $p)) (i32.const 10000))))
+    (; An unaligned i64 straddles three words ;)
+    (i64.store offset=3 align=1 (local.get $p) (i64.const 0x0102030405060708))
+    (if (i64.eq (i64.load offset=3 align=1 (local.get $p)) (i64.const 0x0102030405060708))
+      (then (local.set $r (i32.add (local.get $r) (i32.const 100000)))))
+    (; The byte after it should still be 0xff ;)
+    (local.set $r
+      (i32.add
+        (local.get $r)
+        (i32.mul (i32.load8_u offset=11 (local.get $p)) (i32.const 1000000))))
+    (; A constant address is known to be aligned even without the hint ;)
+    (i32.store align=1 (i32.const 1012) (i32.const 7))
+    (local.set $r
+      (i32.add (local.get $r) (i32.load align=1 (i32.const 1012))))
+    (call $store
+      (i32.mul (global.get $id) (i32.const 4))
+      (local.get $r)))
+)